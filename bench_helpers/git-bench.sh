@@ -1,5 +1,11 @@
 #!/bin/bash -eu
+# Usage: git-bench.sh CMD [cbdr diff options...]
+#
+# Benchmarks CMD at HEAD against the commit where HEAD^ diverged from
+# upstream, and exits with status 2 if HEAD is slower.  Set TIMEOUT to
+# change how long to sample for (default 5m).
 benchprog=$1; shift;
-base=$(git merge-base HEAD^ @{u})
-head=$(git rev-parse HEAD)
-cbdr sample $benchprog $base $head | cbdr diff $@ $base,$head
+base=$(git rev-parse --short "$(git merge-base HEAD^ @{u})")
+head=$(git rev-parse --short HEAD)
+cbdr sample --git-base=$base --git-head=$head --run="$benchprog" --timeout=${TIMEOUT:-5m} |
+    cbdr diff "$@" $base,$head
@@ -1,5 +1,8 @@
 #!/bin/bash -eu
 
+# NOTE: `cbdr sample --counters=all` collects the same counters without
+# shelling out to perf.
+#
 # We're using `time` to measure the max RSS, but note that it includes the
 # RSS of the `perf` program too.
 perf stat --field-separator="," -- \
@@ -1,3 +1,4 @@
+use crate::input;
 use crate::label::*;
 use crate::pretty;
 use anyhow::{bail, Result};
@@ -14,9 +15,6 @@ pub struct Options {
     /// The significance level of the confidence intervals
     #[bpaf(long, short, argument("P-VALUE"), fallback(99.9), display_fallback)]
     significance: f64,
-    // /// The target CI width.  Applies to the 95% CI; units are percent of base.
-    // #[structopt(long)]
-    // threshold: Option<f64>,
     deny_positive: bool,
     /// A "base" label.  If specified, all labels will be compared to this.
     #[bpaf(argument("LABEL"))]
@@ -60,11 +58,8 @@ pub fn analyze(opts: Options) -> Result<()> {
         let _ = Bench::from(x.as_str());
     }
 
-    let mut rdr = csv::Reader::from_reader(std::io::stdin());
-    let mut headers = rdr.headers().unwrap().into_iter();
-    let first = headers.next().unwrap();
-    info!("Assuming \"{}\" column is the benchmark name", first);
-    init_metrics(headers.map(|x| x.to_string()).collect());
+    let rdr = input::Reader::new(std::io::stdin())?;
+    let mut n_failures = 0;
     let mut measurements = Measurements::default();
 
     let stdout = std::io::stdout();
@@ -72,12 +67,13 @@ pub fn analyze(opts: Options) -> Result<()> {
     let mut stdout = liveterm::TermPrinter::new(stdout.lock());
 
     let mut last_print = Instant::now();
-    for row in rdr.into_records() {
+    for row in rdr.rows() {
         let row = row?;
-        let mut row = row.into_iter();
-        let bench = Bench::from(row.next().unwrap());
-        let values = row.map(|x| x.parse().unwrap());
-        measurements.update(bench, values);
+        if !row.succeeded {
+            n_failures += 1;
+            continue;
+        }
+        measurements.update(row.bench, row.values.into_iter());
 
         if interactive && last_print.elapsed() > Duration::from_millis(100) {
             last_print = Instant::now();
@@ -89,21 +85,6 @@ pub fn analyze(opts: Options) -> Result<()> {
             stdout.clear()?;
             stdout.buf = out;
             stdout.print()?;
-
-            // // Check to see if we're finished
-            // if let Some(threshold) = opts.threshold {
-            //     let worst = diff
-            //         .diffs
-            //         .iter()
-            //         .flat_map(|diff| stats.iter().map(move |stat| *diff.cis.get(stat)?))
-            //         .map(|x| x.map_or(std::f64::INFINITY, |x| x.r95_pc()))
-            //         .fold(std::f64::NEG_INFINITY, f64::max);
-            //     if worst < threshold {
-            //         break;
-            //     } else {
-            //         info!("Threshold not reached: {}% > {}%", worst, threshold);
-            //     }
-            // }
         }
     }
 
@@ -120,6 +101,9 @@ pub fn analyze(opts: Options) -> Result<()> {
     } else {
         println!("{}", out);
     }
+    if n_failures > 0 {
+        warn!("Ignored {} failed runs", n_failures);
+    }
 
     if opts.deny_positive {
         for (from, to) in opts.pairs() {
@@ -135,44 +119,60 @@ pub fn analyze(opts: Options) -> Result<()> {
     Ok(())
 }
 
+/// Running stats for every (benchmark, metric) pair
+#[derive(Default)]
 pub struct Measurements {
-    msmts: Vec<behrens_fisher::StatsBuilder>,
-    stride: usize, // a global constant, cached here for speed
+    /// Indexed by benchmark, then by metric
+    msmts: Vec<Vec<behrens_fisher::StatsBuilder>>,
+    /// The number of runs of each benchmark
+    runs: Vec<usize>,
 }
 
-impl Default for Measurements {
-    fn default() -> Measurements {
-        Measurements {
-            msmts: vec![],
-            stride: all_metrics().count(),
-        }
+impl Measurements {
+    /// False if `bench` has never been updated
+    pub fn contains(&self, bench: Bench) -> bool {
+        self.runs(bench) > 0
     }
-}
 
-impl Measurements {
-    pub fn bench_stats(&self, bench: Bench) -> &[behrens_fisher::StatsBuilder] {
-        &self.msmts[bench.0 * self.stride..(bench.0 + 1) * self.stride]
+    pub fn runs(&self, bench: Bench) -> usize {
+        self.runs.get(bench.0).copied().unwrap_or(0)
+    }
+
+    /// Empty if `bench` has never reported `metric`
+    pub fn stats(&self, bench: Bench, metric: Metric) -> behrens_fisher::StatsBuilder {
+        self.msmts
+            .get(bench.0)
+            .and_then(|x| x.get(metric.0))
+            .copied()
+            .unwrap_or_default()
     }
 
-    pub fn update(&mut self, bench: Bench, new_measurements: impl Iterator<Item = f64>) {
-        let start = self.stride * bench.0;
-        let end = self.stride * (bench.0 + 1);
-        if self.msmts.len() < end {
-            self.msmts
-                .resize_with(end, behrens_fisher::StatsBuilder::default);
+    /// Records a run.  It doesn't need to include every metric.
+    pub fn update(&mut self, bench: Bench, new_measurements: impl Iterator<Item = (Metric, f64)>) {
+        if self.msmts.len() <= bench.0 {
+            self.msmts.resize_with(bench.0 + 1, Vec::new);
+            self.runs.resize(bench.0 + 1, 0);
         }
-        for (stats, msmt) in self.msmts[start..end].iter_mut().zip(new_measurements) {
-            stats.update(msmt);
+        self.runs[bench.0] += 1;
+        let stats = &mut self.msmts[bench.0];
+        for (metric, msmt) in new_measurements {
+            if stats.len() <= metric.0 {
+                stats.resize_with(metric.0 + 1, behrens_fisher::StatsBuilder::default);
+            }
+            stats[metric.0].update(msmt);
         }
     }
 
+    /// One CI for each metric, in the same order as `all_metrics()`
     pub fn diff(&self, from: Bench, to: Bench) -> Vec<DiffCI> {
-        self.bench_stats(from)
-            .iter()
-            .copied()
-            .zip(self.bench_stats(to).iter().copied())
-            .map(|(from, to)| DiffCI(from.into(), to.into()))
-            .collect::<Vec<_>>()
+        all_metrics()
+            .map(|metric| {
+                DiffCI(
+                    self.stats(from, metric).into(),
+                    self.stats(to, metric).into(),
+                )
+            })
+            .collect()
     }
 }
 
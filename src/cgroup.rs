@@ -0,0 +1,234 @@
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::ErrorKind;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+/// The controllers we enable for the runs' cgroups, if they're available
+const CONTROLLERS: &[&str] = &["cpu", "memory", "io", "pids"];
+
+/// A cgroup v2 directory under which we create a fresh cgroup for each run.
+/// It must be one we're allowed to manage (eg. one delegated to us by
+/// systemd), and it mustn't have any processes in it.
+#[derive(Clone, Debug)]
+pub struct CgroupRoot(PathBuf);
+
+impl CgroupRoot {
+    /// Checks that `path` is usable, and enables the controllers we need in
+    /// its children
+    pub fn new(path: &Path) -> Result<CgroupRoot> {
+        let controllers = std::fs::read_to_string(path.join("cgroup.controllers"))
+            .with_context(|| format!("{} isn't a cgroup v2 directory", path.display()))?;
+        // Processes can only live in leaf cgroups (except for the root
+        // cgroup, which doesn't have a cgroup.type)
+        let procs = std::fs::read_to_string(path.join("cgroup.procs"))?;
+        if !procs.trim().is_empty() && path.join("cgroup.type").exists() {
+            bail!(
+                "{}: There are already processes in this cgroup.  Please give an empty one.",
+                path.display()
+            );
+        }
+        let available = controllers.split_whitespace().collect::<Vec<_>>();
+        let (enable, missing): (Vec<&str>, Vec<&str>) =
+            CONTROLLERS.iter().partition(|x| available.contains(x));
+        if !missing.is_empty() {
+            eprintln!(
+                "Warning: These controllers aren't available in {}, so their stats won't be recorded: {}",
+                path.display(),
+                missing.join(", ")
+            );
+        }
+        for controller in enable {
+            std::fs::write(
+                path.join("cgroup.subtree_control"),
+                format!("+{}", controller),
+            )
+            .with_context(|| {
+                format!(
+                    "Enabling the {} controller in {}",
+                    controller,
+                    path.display()
+                )
+            })?;
+        }
+        Ok(CgroupRoot(path.to_owned()))
+    }
+
+    /// Creates a new, empty cgroup.  It's removed when dropped.
+    pub fn create(&self) -> Result<Cgroup> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = self.0.join(format!("cbdr-{}-{}", std::process::id(), n));
+        std::fs::create_dir(&path).with_context(|| format!("Creating {}", path.display()))?;
+        // If this fails, dropping `cgroup` removes the directory again
+        let mut cgroup = Cgroup { path, procs: None };
+        let procs = cgroup.path.join("cgroup.procs");
+        cgroup.procs = Some(
+            File::options()
+                .write(true)
+                .open(&procs)
+                .with_context(|| format!("Opening {}", procs.display()))?,
+        );
+        Ok(cgroup)
+    }
+}
+
+/// A cgroup for a single run
+pub struct Cgroup {
+    path: PathBuf,
+    procs: Option<File>,
+}
+
+impl Cgroup {
+    /// Makes `cmd` join this cgroup when it's spawned.  Anything it spawns
+    /// will be in the cgroup too.
+    ///
+    /// The move happens between fork and exec, so it's included in the
+    /// run's wall-clock time.  Spawning straight into the cgroup with
+    /// `clone3(CLONE_INTO_CGROUP)` would avoid that, but `Command` can't do
+    /// it.
+    pub fn add(&self, cmd: &mut Command) {
+        let fd = self.procs.as_ref().unwrap().as_raw_fd();
+        // Writing "0" to cgroup.procs moves the writer.  The fd is
+        // close-on-exec, but the child has a copy of it until it execs.
+        unsafe {
+            cmd.pre_exec(move || {
+                if libc::write(fd, b"0".as_ptr() as *const libc::c_void, 1) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+
+    /// Reads the cgroup's stats into `out`.  Stats whose controller isn't
+    /// enabled are skipped.
+    pub fn stats(&self, out: &mut BTreeMap<String, f64>) -> Result<()> {
+        if let Some(x) = self.read("cpu.stat")? {
+            let stat = parse_flat_keyed(&x);
+            let millis = |key| stat.get(key).map(|&x| x / 1000.);
+            let mut insert = |metric: &str, x| {
+                if let Some(x) = x {
+                    out.insert(metric.into(), x);
+                }
+            };
+            insert("cgroup_cpu_millis", millis("usage_usec"));
+            insert("cgroup_user_cpu_millis", millis("user_usec"));
+            insert("cgroup_kernel_cpu_millis", millis("system_usec"));
+        }
+        if let Some(x) = self.read("memory.peak")? {
+            let bytes = x.trim().parse::<f64>().context("Parsing memory.peak")?;
+            out.insert("cgroup_memory_peak_kb".into(), bytes / 1024.);
+        }
+        if let Some(x) = self.read("io.stat")? {
+            let stat = parse_io_stat(&x);
+            let mut insert = |metric: &str, key| {
+                out.insert(metric.into(), stat.get(key).copied().unwrap_or(0.));
+            };
+            insert("cgroup_read_bytes", "rbytes");
+            insert("cgroup_write_bytes", "wbytes");
+            insert("cgroup_read_ios", "rios");
+            insert("cgroup_write_ios", "wios");
+        }
+        if let Some(x) = self.read("pids.peak")? {
+            let peak = x.trim().parse::<f64>().context("Parsing pids.peak")?;
+            out.insert("cgroup_pids_peak".into(), peak);
+        }
+        Ok(())
+    }
+
+    /// Returns `None` if the file doesn't exist
+    fn read(&self, name: &str) -> Result<Option<String>> {
+        match std::fs::read_to_string(self.path.join(name)) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Reading {}/{}", self.path.display(), name)),
+        }
+    }
+
+    /// Kills anything still running in the cgroup (eg. daemons which the
+    /// benchmark started) and removes it
+    fn destroy(&mut self) -> Result<()> {
+        self.procs = None;
+        let start = Instant::now();
+        loop {
+            // cgroup.kill was added in Linux 5.14.  On older kernels we kill
+            // the processes one by one.
+            if std::fs::write(self.path.join("cgroup.kill"), "1").is_err() {
+                for pid in self.read("cgroup.procs")?.unwrap_or_default().lines() {
+                    let pid = pid.parse::<libc::pid_t>()?;
+                    unsafe { libc::kill(pid, libc::SIGKILL) };
+                }
+            }
+            // This fails with EBUSY until all the processes have exited
+            match std::fs::remove_dir(&self.path) {
+                Ok(()) => return Ok(()),
+                Err(e) if e.raw_os_error() == Some(libc::EBUSY) => (),
+                Err(e) => return Err(e.into()),
+            }
+            if start.elapsed() > Duration::from_secs(10) {
+                bail!("Processes in the cgroup wouldn't die");
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for Cgroup {
+    fn drop(&mut self) {
+        if let Err(e) = self.destroy() {
+            eprintln!("Warning: Removing {}: {:#}", self.path.display(), e);
+        }
+    }
+}
+
+/// Parses files like cpu.stat, which contain lines like "usage_usec 1234"
+fn parse_flat_keyed(x: &str) -> BTreeMap<&str, f64> {
+    x.lines()
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key, value.trim().parse().ok()?))
+        })
+        .collect()
+}
+
+/// Parses io.stat, which contains lines like "8:0 rbytes=1 wbytes=2 ...",
+/// summing over all devices
+fn parse_io_stat(x: &str) -> BTreeMap<&str, f64> {
+    let mut ret = BTreeMap::new();
+    for line in x.lines() {
+        for field in line.split_whitespace().skip(1) {
+            let Some((key, value)) = field.split_once('=') else {
+                continue;
+            };
+            if let Ok(value) = value.parse::<f64>() {
+                *ret.entry(key).or_default() += value;
+            }
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cpu = parse_flat_keyed("usage_usec 1500\nuser_usec 1000\nsystem_usec 500\n");
+        assert_eq!(cpu["usage_usec"], 1500.);
+        assert_eq!(cpu["system_usec"], 500.);
+        let io = parse_io_stat(
+            "8:0 rbytes=100 wbytes=20 rios=3 wios=1 dbytes=0 dios=0\n\
+             259:0 rbytes=50 wbytes=0 rios=2 wios=0 dbytes=0 dios=0\n",
+        );
+        assert_eq!(io["rbytes"], 150.);
+        assert_eq!(io["rios"], 5.);
+        assert_eq!(io["wbytes"], 20.);
+    }
+}
@@ -0,0 +1,251 @@
+use crate::cpus::CpuList;
+use crate::extract::Extractor;
+use crate::sample::{Counters, Format, Policy};
+use crate::schedule::Schedule;
+use anyhow::{bail, Context, Result};
+use serde::{de::Error, Deserialize, Deserializer};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// The contents of a `cbdr sample --config` file.  Every setting can also be
+/// given on the command line, which takes precedence.
+///
+/// ```toml
+/// timeout = "10m"
+/// threshold = 2
+/// seed = 42
+/// output = "results.csv"
+/// prepare = "rm -rf db"
+///
+/// [[target]]
+/// name = "base"
+/// command = "./app bench"
+/// cwd = "../base"
+/// env = { RUST_LOG = "off" }
+///
+/// [[target]]
+/// name = "feature"
+/// script = "./bench.sh"
+/// args = ["feature"]
+/// ```
+#[derive(Deserialize, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    #[serde(default, deserialize_with = "from_str")]
+    pub timeout: Option<humantime::Duration>,
+    pub threshold: Option<f64>,
+    pub threshold_metric: Option<String>,
+    pub significance: Option<f64>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub run_timeout: Option<humantime::Duration>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub on_timeout: Option<Policy>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub on_failure: Option<Policy>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub schedule: Option<Schedule>,
+    pub seed: Option<u64>,
+    pub schedule_file: Option<PathBuf>,
+    pub jobs: Option<usize>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub cpus: Option<CpuList>,
+    /// Where to write the results.  Defaults to stdout.
+    pub output: Option<PathBuf>,
+    /// Add the results to the end of this file
+    pub append: Option<PathBuf>,
+    #[serde(default, deserialize_with = "from_str")]
+    pub format: Option<Format>,
+    #[serde(default)]
+    pub run_metadata: bool,
+    pub warmup: Option<usize>,
+    pub warmup_until_stable: Option<f64>,
+    pub warmup_window: Option<usize>,
+    pub warmup_metric: Option<String>,
+    #[serde(default)]
+    pub keep_warmup: bool,
+    /// Metrics to extract from the targets' output, as name = "REGEX"
+    #[serde(default)]
+    pub metrics: BTreeMap<String, String>,
+    /// Performance counters to collect, eg. "instructions,cycles"
+    #[serde(default, deserialize_with = "from_str")]
+    pub counters: Option<Counters>,
+    /// Run each run in a fresh cgroup under this one
+    pub cgroup: Option<PathBuf>,
+    /// Compare two git revisions; see `cbdr sample --git-base`
+    pub git_base: Option<String>,
+    pub git_head: Option<String>,
+    pub build: Option<String>,
+    pub run: Option<String>,
+    /// Run before each run of every target (unless the target has its own)
+    pub prepare: Option<String>,
+    /// Run after each run of every target (unless the target has its own)
+    pub cleanup: Option<String>,
+    /// Don't pass our environment on to any of the targets
+    #[serde(default)]
+    pub clear_env: bool,
+    #[serde(default, rename = "target")]
+    pub targets: Vec<Target>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Target {
+    pub name: String,
+    /// Run with `/bin/sh -c`.  Its execution time will be measured.
+    pub command: Option<String>,
+    /// Run directly.  It should print its measurements as JSON.
+    pub script: Option<String>,
+    /// Started once and kept running.  See `cbdr sample --server`.
+    pub server: Option<String>,
+    /// Arguments to pass to `script`
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// The directory to run the target in
+    pub cwd: Option<PathBuf>,
+    /// Extra environment variables to set
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Don't pass our environment on; only use `env`
+    #[serde(default)]
+    pub clear_env: bool,
+    pub prepare: Option<String>,
+    pub cleanup: Option<String>,
+}
+
+fn from_str<'de, D, T>(d: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    match Option::<String>::deserialize(d)? {
+        Some(x) => x.parse().map(Some).map_err(D::Error::custom),
+        None => Ok(None),
+    }
+}
+
+impl Config {
+    /// Reads and validates a config file.  Relative paths in the file are
+    /// taken to be relative to the file itself.
+    pub fn load(path: &Path) -> Result<Config> {
+        let contents =
+            std::fs::read_to_string(path).with_context(|| format!("Reading {}", path.display()))?;
+        let mut config: Config =
+            toml::from_str(&contents).with_context(|| format!("Parsing {}", path.display()))?;
+        let dir = path.parent().unwrap_or(Path::new(""));
+        let resolve = |x: &mut PathBuf| *x = dir.join(&*x);
+        config.output.as_mut().map(resolve);
+        config.append.as_mut().map(resolve);
+        config.schedule_file.as_mut().map(resolve);
+        for target in &mut config.targets {
+            target.cwd.as_mut().map(resolve);
+        }
+        config
+            .validate()
+            .with_context(|| format!("Validating {}", path.display()))?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<()> {
+        let mut names = BTreeSet::new();
+        for target in &self.targets {
+            if !names.insert(&target.name) {
+                bail!("There are two targets called \"{}\"", target.name);
+            }
+            match (&target.command, &target.script, &target.server) {
+                (_, None, _) if !target.args.is_empty() => bail!(
+                    "Target \"{}\": \"args\" can only be used with \"script\"",
+                    target.name
+                ),
+                (Some(_), None, None) | (None, Some(_), None) | (None, None, Some(_)) => (),
+                _ => bail!(
+                    "Target \"{}\": Expected exactly one of \"command\", \"script\", or \"server\"",
+                    target.name
+                ),
+            }
+            if let Some(cwd) = &target.cwd {
+                if !cwd.is_dir() {
+                    bail!(
+                        "Target \"{}\": \"cwd\" ({}) isn't a directory",
+                        target.name,
+                        cwd.display()
+                    );
+                }
+            }
+        }
+        if let Some(x) = self.significance {
+            if x <= 0. || x >= 100. {
+                bail!("Significance level must be between 0 and 100");
+            }
+        }
+        for (name, regex) in &self.metrics {
+            Extractor::new(name, regex)?;
+        }
+        if self.jobs == Some(0) {
+            bail!("\"jobs\" must be at least 1");
+        }
+        if self.warmup == Some(0) {
+            bail!("\"warmup\" must be at least 1");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time_cmd::Counter;
+
+    fn parse(x: &str) -> Result<Config> {
+        let config: Config = toml::from_str(x)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn test_parse() {
+        let config = parse(
+            r#"
+            timeout = "1m20s"
+            on_failure = "skip"
+            counters = "instructions,task-clock"
+            [[target]]
+            name = "base"
+            command = "true"
+            env = { FOO = "bar" }
+            clear_env = true
+            [[target]]
+            name = "feature"
+            script = "./bench.sh"
+            args = ["x"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.timeout.unwrap().as_secs(), 80);
+        assert_eq!(config.on_failure, Some(Policy::Skip));
+        let counters = config.counters.unwrap().0;
+        assert_eq!(counters, [Counter::Instructions, Counter::TaskClock]);
+        assert_eq!(config.targets.len(), 2);
+        assert_eq!(config.targets[0].env["FOO"], "bar");
+        assert!(config.targets[0].clear_env && !config.targets[1].clear_env);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let target = |x: &str| parse(&format!("[[target]]\nname = \"x\"\n{}", x));
+        assert!(target("server = \"a\"").is_ok());
+        assert!(target("").is_err());
+        assert!(target("command = \"a\"\nscript = \"b\"").is_err());
+        assert!(target("command = \"a\"\nargs = [\"b\"]").is_err());
+        assert!(target("command = \"a\"\ntypo = 1").is_err());
+        assert!(target("command = \"a\"\ncwd = \"nope\"").is_err());
+        assert!(target("command = \"a\"\ncwd = \"src\"").is_ok());
+        assert!(parse("on_failure = \"explode\"").is_err());
+        assert!(parse("warmup = 0").is_err());
+        assert!(parse("counters = \"instructions,bogons\"").is_err());
+        let dup = "[[target]]\nname = \"x\"\ncommand = \"a\"\n";
+        assert!(parse(&format!("{}{}", dup, dup)).is_err());
+    }
+}
@@ -0,0 +1,115 @@
+use anyhow::{anyhow, bail, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// A set of CPUs, given in the same format as taskset(1), eg. "0-3,8,10-11"
+#[derive(Clone, Debug, PartialEq)]
+pub struct CpuList(Vec<usize>);
+
+impl FromStr for CpuList {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<CpuList> {
+        let parse = |x: &str| {
+            x.trim()
+                .parse::<usize>()
+                .map_err(|_| anyhow!("Not a CPU number: {:?}", x))
+        };
+        let mut cpus = vec![];
+        for range in x.split(',') {
+            match range.split_once('-') {
+                Some((from, to)) => {
+                    let (from, to) = (parse(from)?, parse(to)?);
+                    if from > to {
+                        bail!("Bad CPU range: {}", range);
+                    }
+                    cpus.extend(from..=to);
+                }
+                None => cpus.push(parse(range)?),
+            }
+        }
+        cpus.sort_unstable();
+        cpus.dedup();
+        Ok(CpuList(cpus))
+    }
+}
+
+impl fmt::Display for CpuList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, cpu) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(f, "{}", cpu)?;
+        }
+        Ok(())
+    }
+}
+
+impl CpuList {
+    /// Divides the CPUs into `n` disjoint sets of (roughly) equal size
+    pub fn split(&self, n: usize) -> Result<Vec<CpuList>> {
+        if self.0.len() < n {
+            bail!("Can't share {} CPUs between {} workers", self.0.len(), n);
+        }
+        let (size, rem) = (self.0.len() / n, self.0.len() % n);
+        let mut rest = &self.0[..];
+        Ok((0..n)
+            .map(|i| {
+                let (xs, ys) = rest.split_at(size + usize::from(i < rem));
+                rest = ys;
+                CpuList(xs.to_vec())
+            })
+            .collect())
+    }
+
+    /// Restricts the current thread (and any processes it spawns from now
+    /// on) to these CPUs
+    #[cfg(target_os = "linux")]
+    pub fn pin_current_thread(&self) -> Result<()> {
+        unsafe {
+            let mut set = std::mem::zeroed::<libc::cpu_set_t>();
+            libc::CPU_ZERO(&mut set);
+            for &cpu in &self.0 {
+                libc::CPU_SET(cpu, &mut set);
+            }
+            let size = std::mem::size_of::<libc::cpu_set_t>();
+            if libc::sched_setaffinity(0, size, &set) != 0 {
+                return Err(std::io::Error::last_os_error().into());
+            }
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn pin_current_thread(&self) -> Result<()> {
+        bail!("CPU pinning is only supported on Linux")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let cpus: CpuList = "0-3,8, 10-11,2".parse().unwrap();
+        assert_eq!(cpus, CpuList(vec![0, 1, 2, 3, 8, 10, 11]));
+        assert!("3-1".parse::<CpuList>().is_err());
+        assert!("x".parse::<CpuList>().is_err());
+    }
+
+    #[test]
+    fn test_split() {
+        let cpus: CpuList = "0-6".parse().unwrap();
+        let sets = cpus.split(3).unwrap();
+        assert_eq!(
+            sets,
+            vec![
+                CpuList(vec![0, 1, 2]),
+                CpuList(vec![3, 4]),
+                CpuList(vec![5, 6])
+            ]
+        );
+        assert!(cpus.split(8).is_err());
+    }
+}
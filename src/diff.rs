@@ -0,0 +1,155 @@
+use crate::analyze::{DiffCI, Measurements};
+use crate::input;
+use crate::label::*;
+use crate::pretty;
+use anyhow::{anyhow, bail, Result};
+use bpaf::Bpaf;
+use log::*;
+use std::str::FromStr;
+
+/// Compares two benchmarks and exits with status 2 if any metric got worse
+/// by more than the budget.  Meant for use in CI.
+#[derive(Bpaf)]
+#[bpaf(command("diff"))]
+pub struct Options {
+    /// The significance level of the confidence intervals
+    #[bpaf(long, short, argument("P-VALUE"), fallback(99.9), display_fallback)]
+    significance: f64,
+    /// How much worse (in percent) a metric may get before it counts as a
+    /// regression.  A metric has regressed if its whole CI is above this.
+    #[bpaf(long, argument("PERCENT"), fallback(0.), display_fallback)]
+    budget: f64,
+    /// Only check these metrics.  By default, every metric is checked.
+    #[bpaf(long("metric"), argument("NAME"))]
+    metrics: Vec<String>,
+    /// The benchmarks to compare, eg. "base,head"
+    #[bpaf(positional("FROM,TO"))]
+    pair: Pair,
+}
+
+#[derive(Clone)]
+struct Pair(String, String);
+impl FromStr for Pair {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Pair> {
+        let (from, to) = x
+            .split_once(',')
+            .ok_or_else(|| anyhow!("Expected two labels separated by a comma"))?;
+        Ok(Pair(from.to_string(), to.to_string()))
+    }
+}
+
+pub fn diff(opts: Options) -> Result<()> {
+    if opts.significance <= 0. || opts.significance >= 100. {
+        bail!("Significance level must be between 0 and 100");
+    }
+    let from = Bench::from(opts.pair.0.as_str());
+    let to = Bench::from(opts.pair.1.as_str());
+
+    let rdr = input::Reader::new(std::io::stdin())?;
+    let mut measurements = Measurements::default();
+    let mut n_failures = 0;
+    for row in rdr.rows() {
+        let row = row?;
+        if !row.succeeded {
+            n_failures += 1;
+            continue;
+        }
+        measurements.update(row.bench, row.values.into_iter());
+    }
+    if n_failures > 0 {
+        warn!("Ignored {} failed runs", n_failures);
+    }
+    // Not `Bench`'s Display, which adds colours
+    for (bench, label) in [(from, &opts.pair.0), (to, &opts.pair.1)] {
+        if !measurements.contains(bench) {
+            bail!("There are no results for \"{}\"", label);
+        }
+    }
+    for name in &opts.metrics {
+        if !all_metrics().any(|x| x.to_string() == *name) {
+            bail!("There's no metric called \"{}\"", name);
+        }
+    }
+
+    let diff = measurements.diff(from, to);
+    let out = pretty::render(
+        &measurements,
+        std::iter::once((from, to, diff.clone())),
+        opts.significance,
+    )?;
+    println!("{}", out);
+
+    let regressed = regressions(&diff, &opts.metrics, opts.significance, opts.budget);
+    for (metric, lo, hi) in &regressed {
+        eprintln!(
+            "{}: {} regressed by {:.1}% to {:.1}% (budget: {}%)",
+            opts.pair.1, metric, lo, hi, opts.budget
+        );
+    }
+    if !regressed.is_empty() {
+        std::process::exit(2);
+    }
+    Ok(())
+}
+
+/// The metrics whose whole CI lies above `budget`, with their CIs.  `diff`
+/// has one CI per metric, as returned by `Measurements::diff()`.  If
+/// `metrics` isn't empty, only those metrics are checked.  A metric whose CI
+/// is undefined (NaN) doesn't count as a regression.
+fn regressions(
+    diff: &[DiffCI],
+    metrics: &[String],
+    significance: f64,
+    budget: f64,
+) -> Vec<(Metric, f64, f64)> {
+    all_metrics()
+        .zip(diff)
+        .filter(|(metric, _)| metrics.is_empty() || metrics.contains(&metric.to_string()))
+        .map(|(metric, ci)| {
+            let (lo, hi) = ci.interval(significance / 100.);
+            (metric, lo, hi)
+        })
+        .filter(|&(_, lo, _)| lo > budget)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_regressions() {
+        let [from, to] = [Bench::from("diff_from"), Bench::from("diff_to")];
+        let [fast, slow, nan] = ["diff_fast", "diff_slow", "diff_nan"].map(Metric::from);
+        let mut msmts = Measurements::default();
+        for i in 0..100 {
+            let noise = (i % 2) as f64;
+            // "slow" gets 10% worse, "fast" gets 10% better, and "nan" has
+            // no variance, so its CI is undefined
+            msmts.update(
+                from,
+                [(slow, 100. + noise), (fast, 100. + noise), (nan, 1.)].into_iter(),
+            );
+            msmts.update(
+                to,
+                [(slow, 110. + noise), (fast, 90. + noise), (nan, 2.)].into_iter(),
+            );
+        }
+        let diff = msmts.diff(from, to);
+        let names = |xs: Vec<(Metric, f64, f64)>| {
+            xs.into_iter()
+                .map(|(m, _, _)| m.to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(names(regressions(&diff, &[], 99.9, 0.)), ["diff_slow"]);
+        assert_eq!(names(regressions(&diff, &[], 99.9, 5.)), ["diff_slow"]);
+        assert!(regressions(&diff, &[], 99.9, 15.).is_empty());
+        let only = |x: &str| [x.to_string()];
+        assert!(regressions(&diff, &only("diff_fast"), 99.9, 0.).is_empty());
+        assert_eq!(
+            names(regressions(&diff, &only("diff_slow"), 99.9, 0.)),
+            ["diff_slow"]
+        );
+    }
+}
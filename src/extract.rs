@@ -0,0 +1,200 @@
+use anyhow::{anyhow, bail, Context, Result};
+use regex::Regex;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Pulls a metric out of a benchmark's output, given as "name=REGEX".  The
+/// value is the regex's first capture group (or the whole match, if it has
+/// no groups).
+#[derive(Clone, Debug)]
+pub struct Extractor {
+    name: String,
+    regex: Regex,
+}
+
+impl FromStr for Extractor {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Extractor> {
+        let (name, regex) = x
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Expected \"name=REGEX\""))?;
+        Extractor::new(name, regex)
+    }
+}
+
+impl fmt::Display for Extractor {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}={}", self.name, self.regex)
+    }
+}
+
+impl Extractor {
+    pub fn new(name: &str, regex: &str) -> Result<Extractor> {
+        Ok(Extractor {
+            name: name.to_string(),
+            regex: Regex::new(regex).with_context(|| format!("Bad regex for {}", name))?,
+        })
+    }
+
+    /// Looks in stdout first, then stderr.  If the regex matches more than
+    /// once, the last match wins (so progress reports are ignored in favour
+    /// of the final figure).  No match means no measurement.
+    pub fn extract(&self, stdout: &str, stderr: &str) -> Result<Option<f64>> {
+        let Some(caps) = [stdout, stderr]
+            .into_iter()
+            .find_map(|out| self.regex.captures_iter(out).last())
+        else {
+            return Ok(None);
+        };
+        let x = caps.get(1).or_else(|| caps.get(0)).unwrap().as_str();
+        let x = x
+            .parse()
+            .with_context(|| format!("{}: Not a number: {:?}", self.name, x))?;
+        Ok(Some(x))
+    }
+}
+
+/// Applies the extractors to the output of a run, adding the results to
+/// `values`.  A match which isn't a number counts as no measurement (with a
+/// warning): it's not worth throwing the whole session away over.
+pub fn extract_all(
+    extractors: &[Extractor],
+    stdout: &[u8],
+    stderr: &[u8],
+    values: &mut BTreeMap<String, f64>,
+) {
+    let stdout = String::from_utf8_lossy(stdout);
+    let stderr = String::from_utf8_lossy(stderr);
+    for x in extractors {
+        match x.extract(&stdout, &stderr) {
+            Ok(Some(value)) => {
+                values.insert(x.name.clone(), value);
+            }
+            Ok(None) => (),
+            Err(e) => eprintln!("Warning: {:#}; leaving it out", e),
+        }
+    }
+}
+
+/// Parses the JSON printed by a benchmark script.  This may be a single
+/// object, an array of objects, or a sequence of objects (eg. one per line);
+/// each object is one measurement.
+///
+/// Nested objects are flattened, so `{"a": {"b": 1}}` gives a metric called
+/// "a.b".  Booleans count as 0 or 1, strings are parsed as numbers, and nulls
+/// are ignored.
+pub fn parse_json(stdout: &[u8]) -> Result<Vec<BTreeMap<String, f64>>> {
+    let mut samples = vec![];
+    for value in serde_json::Deserializer::from_slice(stdout).into_iter::<Value>() {
+        match value.context("Parsing JSON")? {
+            Value::Array(xs) => samples.extend(xs),
+            x => samples.push(x),
+        }
+    }
+    if samples.is_empty() {
+        bail!("Expected a JSON object, but there was no output");
+    }
+    samples
+        .into_iter()
+        .map(|value| {
+            let Value::Object(_) = value else {
+                bail!("Expected a JSON object, got: {}", value);
+            };
+            let mut values = BTreeMap::new();
+            flatten(String::new(), value, &mut values)?;
+            Ok(values)
+        })
+        .collect()
+}
+
+fn flatten(name: String, value: Value, out: &mut BTreeMap<String, f64>) -> Result<()> {
+    let x = match value {
+        Value::Object(fields) => {
+            for (key, value) in fields {
+                let name = if name.is_empty() {
+                    key
+                } else {
+                    format!("{}.{}", name, key)
+                };
+                flatten(name, value, out)?;
+            }
+            return Ok(());
+        }
+        Value::Null => return Ok(()),
+        Value::Bool(x) => f64::from(u8::from(x)),
+        Value::Number(x) => x
+            .as_f64()
+            .ok_or_else(|| anyhow!("{}: Not representable as a float: {}", name, x))?,
+        Value::String(x) => x
+            .trim()
+            .parse()
+            .with_context(|| format!("{}: Not a number: {:?}", name, x))?,
+        Value::Array(_) => bail!("{}: Arrays aren't supported", name),
+    };
+    out.insert(name, x);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_json() {
+        let json = r#"{
+            "dump_pcap": { "task_clock": 288.5, "cycles": 1231 },
+            "ok": true,
+            "rps": " 12.5 ",
+            "skipped": null
+        }"#;
+        let values = parse_json(json.as_bytes()).unwrap().remove(0);
+        let expected = [
+            ("dump_pcap.cycles", 1231.),
+            ("dump_pcap.task_clock", 288.5),
+            ("ok", 1.),
+            ("rps", 12.5),
+        ];
+        let expected = expected.iter().map(|&(k, v)| (k.to_string(), v)).collect();
+        assert_eq!(values, expected);
+        assert!(parse_json(br#"{"a": "fast"}"#).is_err());
+        assert!(parse_json(br#"{"a": [1]}"#).is_err());
+        assert!(parse_json(b"1").is_err());
+        assert!(parse_json(b"").is_err());
+    }
+
+    #[test]
+    fn test_multiple() {
+        let n = |x: &[u8]| parse_json(x).unwrap().len();
+        assert_eq!(n(br#"{"a": 1}"#), 1);
+        assert_eq!(n(br#"[{"a": 1}, {"a": 2}]"#), 2);
+        assert_eq!(n(b"{\"a\": 1}\n{\"a\": 2}\n{\"a\": 3}\n"), 3);
+        assert!(parse_json(b"[1, 2]").is_err());
+    }
+
+    #[test]
+    fn test_extract() {
+        let rps: Extractor = r"rps=processed (\S+) req/s".parse().unwrap();
+        let out = "processed 10 req/s\nprocessed 1234.5 req/s\n";
+        assert_eq!(rps.extract(out, "").unwrap(), Some(1234.5));
+        assert_eq!(rps.extract("", out).unwrap(), Some(1234.5));
+        assert_eq!(rps.extract("nothing", "").unwrap(), None);
+        assert!(rps.extract("processed lots req/s", "").is_err());
+        let whole: Extractor = r"n=\d+".parse().unwrap();
+        assert_eq!(whole.extract("n: 42", "").unwrap(), Some(42.));
+        assert!("no-equals".parse::<Extractor>().is_err());
+        assert!("bad=(".parse::<Extractor>().is_err());
+
+        let mut values = BTreeMap::new();
+        let val: Extractor = r"val=val (\S+)".parse().unwrap();
+        extract_all(
+            &[val.clone(), rps],
+            b"val abc\nprocessed 3 req/s",
+            b"",
+            &mut values,
+        );
+        assert_eq!(values.len(), 1);
+        assert_eq!(values["rps"], 3.);
+    }
+}
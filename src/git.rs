@@ -0,0 +1,113 @@
+use crate::interrupt;
+use anyhow::{bail, Context, Result};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::{Mutex, Once};
+
+/// A checkout of a particular commit, in a temporary `git worktree`.  It's
+/// removed when dropped, or if we're killed by SIGINT or SIGTERM.
+pub struct Worktree {
+    pub short_sha: String,
+    pub path: PathBuf,
+}
+
+/// Gets the full SHA of the commit which `rev` refers to, in the repo
+/// containing the current directory
+pub fn resolve(rev: &str) -> Result<String> {
+    git(&["rev-parse", "--verify", &format!("{}^{{commit}}", rev)])
+        .with_context(|| format!("Resolving \"{}\"", rev))
+}
+
+impl Worktree {
+    pub fn new(sha: &str) -> Result<Worktree> {
+        static INIT: Once = Once::new();
+        INIT.call_once(|| {
+            remove_stale();
+            interrupt::on_interrupt(remove_live);
+        });
+        let short_sha = git(&["rev-parse", "--short", sha])?;
+        let path = std::env::temp_dir().join(format!("cbdr-{}-{}", std::process::id(), short_sha));
+        eprintln!("Checking out {} in {}", short_sha, path.display());
+        // Hold the lock so that a signal can't sneak in between creating the
+        // worktree and recording it
+        let mut live = LIVE.lock().unwrap();
+        git(&["worktree", "add", "--detach", &path.to_string_lossy(), sha])
+            .with_context(|| format!("Creating a worktree for {}", short_sha))?;
+        live.push(path.clone());
+        Ok(Worktree { short_sha, path })
+    }
+}
+
+impl Drop for Worktree {
+    fn drop(&mut self) {
+        let mut live = LIVE.lock().unwrap();
+        remove(&self.path);
+        live.retain(|x| *x != self.path);
+    }
+}
+
+/// The worktrees which haven't been removed yet
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(vec![]);
+
+fn remove(path: &Path) {
+    let path = path.to_string_lossy();
+    if let Err(e) = git(&["worktree", "remove", "--force", &path]) {
+        eprintln!("Warning: Removing the worktree in {}: {:#}", path, e);
+    }
+}
+
+/// Removes the worktrees of earlier sessions which were killed before they
+/// could clean up (eg. with SIGKILL)
+fn remove_stale() {
+    let Ok(list) = git(&["worktree", "list", "--porcelain"]) else {
+        return;
+    };
+    let tmp = std::env::temp_dir();
+    for path in list.lines().filter_map(|x| x.strip_prefix("worktree ")) {
+        let path = Path::new(path);
+        let Some(name) = path
+            .strip_prefix(&tmp)
+            .ok()
+            .and_then(|x| x.to_str())
+            .and_then(|x| x.strip_prefix("cbdr-"))
+        else {
+            continue;
+        };
+        let Some(pid) = name.split_once('-').and_then(|(x, _)| x.parse().ok()) else {
+            continue;
+        };
+        let alive = unsafe { libc::kill(pid, 0) } == 0
+            || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM);
+        if !alive {
+            eprintln!("Removing a stale worktree in {}", path.display());
+            remove(path);
+        }
+    }
+}
+
+/// Removes the worktrees which are still around.  This is for when we've
+/// been interrupted, so it doesn't bother releasing the lock: no more
+/// worktrees should be created.
+fn remove_live() {
+    let live = LIVE.lock().unwrap_or_else(|e| e.into_inner());
+    for path in live.iter() {
+        remove(path);
+    }
+    std::mem::forget(live);
+}
+
+/// Runs git and returns its output, trimmed
+fn git(args: &[&str]) -> Result<String> {
+    let out = Command::new("git")
+        .args(args)
+        .output()
+        .context("Running git")?;
+    if !out.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args[0],
+            String::from_utf8_lossy(&out.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8(out.stdout)?.trim().to_string())
+}
@@ -0,0 +1,309 @@
+use crate::label::*;
+use crate::preamble::Preamble;
+use anyhow::{Context, Result};
+use log::*;
+use std::io::{BufReader, Read};
+
+/// Columns written by `cbdr sample --on-failure=record` which say whether
+/// the run succeeded.  They aren't metrics.
+pub const STATUS_COLUMNS: &[&str] = &["exit_status", "signal"];
+
+/// Other columns written by `cbdr sample` which aren't metrics
+pub const INFO_COLUMNS: &[&str] = &[
+    "run_id",
+    "round",
+    "worker",
+    "start_unix_ns",
+    "end_unix_ns",
+    "phase",
+];
+
+pub struct Row {
+    pub bench: Bench,
+    /// The measurements.  Some metrics may be missing.
+    pub values: Vec<(Metric, f64)>,
+    /// If false, the run failed and `values` are meaningless
+    pub succeeded: bool,
+}
+
+/// How the measurements are laid out
+enum Layout {
+    /// One row per run, with a column for each metric
+    Wide { metrics: Vec<(usize, Metric)> },
+    /// One row per measurement (`cbdr sample --format=long`).  Rows with the
+    /// same run_id belong to the same run.
+    Long {
+        run_id: usize,
+        metric: usize,
+        value: usize,
+    },
+}
+
+/// What each column contains
+struct Columns {
+    bench: usize,
+    layout: Layout,
+    status: Vec<usize>,
+    /// Rows with "warmup" in this column are skipped
+    phase: Option<usize>,
+}
+
+impl Columns {
+    /// Interns the metrics
+    fn new(headers: &csv::StringRecord) -> Result<Columns> {
+        let find = |name: &str| headers.iter().position(|x| x == name);
+        // The benchmark name is in the first column (not counting run_id)
+        let bench = headers
+            .iter()
+            .position(|x| x != "run_id")
+            .context("The input has no columns")?;
+        let status = STATUS_COLUMNS.iter().filter_map(|x| find(x)).collect();
+        let layout = match (find("metric"), find("value")) {
+            (Some(metric), Some(value)) => Layout::Long {
+                run_id: find("run_id").context("Long-format input needs a run_id column")?,
+                metric,
+                value,
+            },
+            _ => Layout::Wide {
+                metrics: headers
+                    .iter()
+                    .enumerate()
+                    .filter(|&(idx, name)| {
+                        idx != bench
+                            && !STATUS_COLUMNS.contains(&name)
+                            && !INFO_COLUMNS.contains(&name)
+                    })
+                    .map(|(idx, name)| (idx, Metric::from(name)))
+                    .collect(),
+            },
+        };
+        Ok(Columns {
+            bench,
+            layout,
+            status,
+            phase: find("phase"),
+        })
+    }
+
+    fn succeeded(&self, row: &csv::StringRecord) -> bool {
+        self.status
+            .iter()
+            .all(|&idx| matches!(row.get(idx), None | Some("" | "0")))
+    }
+}
+
+/// Reads the CSV produced by `cbdr sample`, in either the wide or the long
+/// format.
+///
+/// If the set of metrics changes part-way through, `cbdr sample` writes a
+/// new header row.  We recognise it by its first column, which matches the
+/// first column of the original header (ie. "benchmark").
+pub struct Reader<R> {
+    rdr: csv::Reader<BufReader<R>>,
+    preamble: Preamble,
+    first_column: String,
+    bench_column: String,
+    columns: Columns,
+}
+
+impl<R: Read> Reader<R> {
+    /// Reads the preamble and the header.  This initialises the metrics,
+    /// and the benchmarks listed in the preamble.
+    pub fn new(rdr: R) -> Result<Reader<R>> {
+        let mut rdr = BufReader::new(rdr);
+        let preamble = Preamble::read(&mut rdr).context("Reading preamble")?;
+        // Intern the labels now, so they're in the order they were given to
+        // `cbdr sample`, rather than the order they happened to run in
+        for bench in &preamble.benchmarks {
+            let _ = Bench::from(bench.label.as_str());
+        }
+        // If multiple files were concatenated, later preambles are ignored
+        let mut rdr = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .flexible(true)
+            .from_reader(rdr);
+        let headers = rdr.headers().context("Reading CSV header")?.clone();
+        let columns = Columns::new(&headers)?;
+        let bench_column = &headers[columns.bench];
+        info!("Assuming \"{}\" column is the benchmark name", bench_column);
+        Ok(Reader {
+            first_column: headers[0].to_string(),
+            bench_column: bench_column.to_string(),
+            preamble,
+            columns,
+            rdr,
+        })
+    }
+
+    pub fn preamble(&self) -> &Preamble {
+        &self.preamble
+    }
+
+    /// The name of the column containing the benchmark labels
+    pub fn bench_column(&self) -> &str {
+        &self.bench_column
+    }
+
+    pub fn rows(self) -> impl Iterator<Item = Result<Row>> {
+        let first_column = self.first_column;
+        let mut columns = self.columns;
+        let mut records = self.rdr.into_records();
+        // In the long format, the run we're in the middle of reading
+        let mut pending: Option<(String, Row)> = None;
+        std::iter::from_fn(move || loop {
+            let row = match records.next() {
+                Some(Ok(row)) => row,
+                Some(Err(e)) => return Some(Err(e.into())),
+                None => return pending.take().map(|(_, row)| Ok(row)),
+            };
+            if row[0] == first_column {
+                debug!("The columns have changed: {:?}", row);
+                columns = match Columns::new(&row) {
+                    Ok(x) => x,
+                    Err(e) => return Some(Err(e)),
+                };
+                continue;
+            }
+            if columns.phase.and_then(|idx| row.get(idx)) == Some("warmup") {
+                continue;
+            }
+            let bench = Bench::from(&row[columns.bench]);
+            let succeeded = columns.succeeded(&row);
+            match &columns.layout {
+                Layout::Wide { metrics } => {
+                    let values = metrics
+                        .iter()
+                        .filter_map(|&(idx, metric)| {
+                            let x = parse_value(bench, row.get(idx).unwrap_or(""));
+                            x.transpose().map(|x| x.map(|x| (metric, x)))
+                        })
+                        .collect::<Result<Vec<_>>>();
+                    return Some(values.map(|values| Row {
+                        bench,
+                        values,
+                        succeeded,
+                    }));
+                }
+                Layout::Long {
+                    run_id,
+                    metric,
+                    value,
+                } => {
+                    let value = match parse_value(bench, &row[*value]) {
+                        Ok(x) => x.map(|x| (Metric::from(&row[*metric]), x)),
+                        Err(e) => return Some(Err(e)),
+                    };
+                    let run_id = &row[*run_id];
+                    let finished = match &mut pending {
+                        Some((id, run)) if id == run_id => {
+                            run.values.extend(value);
+                            run.succeeded &= succeeded;
+                            continue;
+                        }
+                        _ => pending.take(),
+                    };
+                    let run = Row {
+                        bench,
+                        values: value.into_iter().collect(),
+                        succeeded,
+                    };
+                    pending = Some((run_id.to_string(), run));
+                    if let Some((_, row)) = finished {
+                        return Some(Ok(row));
+                    }
+                }
+            }
+        })
+    }
+}
+
+/// Empty means the benchmark didn't report this metric
+fn parse_value(bench: Bench, x: &str) -> Result<Option<f64>> {
+    if x.is_empty() {
+        return Ok(None);
+    }
+    let x = x
+        .parse()
+        .with_context(|| format!("{}: Not a number: {}", bench, x))?;
+    Ok(Some(x))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_header() {
+        let csv = "# seed: 1
+benchmark,round,in_a
+foobar,1,1
+benchmark,round,in_a,in_b
+foobar,2,2,3
+barqux,2,,4
+";
+        let rows = Reader::new(csv.as_bytes())
+            .unwrap()
+            .rows()
+            .map(|row| {
+                let row = row.unwrap();
+                let values = row.values.into_iter();
+                values.map(|(m, x)| (m.to_string(), x)).collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let (a, b) = ("in_a".to_string(), "in_b".to_string());
+        assert_eq!(
+            rows,
+            vec![
+                vec![(a.clone(), 1.)],
+                vec![(a, 2.), (b.clone(), 3.)],
+                vec![(b, 4.)],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_long() {
+        let csv = "run_id,benchmark,exit_status,signal,metric,value
+1,foobar,0,,in_c,1
+1,foobar,0,,in_d,2
+2,barqux,0,,in_c,3
+3,barqux,1,,,
+4,foobar,0,,in_d,4
+";
+        let rows = Reader::new(csv.as_bytes())
+            .unwrap()
+            .rows()
+            .map(|row| {
+                let row = row.unwrap();
+                let values = row.values.into_iter();
+                let values = values.map(|(m, x)| (m.to_string(), x)).collect::<Vec<_>>();
+                (row.succeeded, values)
+            })
+            .collect::<Vec<_>>();
+        let (c, d) = ("in_c".to_string(), "in_d".to_string());
+        assert_eq!(
+            rows,
+            vec![
+                (true, vec![(c.clone(), 1.), (d.clone(), 2.)]),
+                (true, vec![(c, 3.)]),
+                (false, vec![]),
+                (true, vec![(d, 4.)]),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_warmup() {
+        let csv = "benchmark,phase,in_e
+foobar,warmup,1
+barqux,warmup,2
+foobar,measure,3
+";
+        let rows = Reader::new(csv.as_bytes())
+            .unwrap()
+            .rows()
+            .map(|row| row.unwrap().values[0].1)
+            .collect::<Vec<_>>();
+        assert_eq!(rows, vec![3.]);
+    }
+}
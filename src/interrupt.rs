@@ -0,0 +1,60 @@
+//! Cleaning up when we're killed by SIGINT or SIGTERM
+
+use std::io::Read;
+use std::os::unix::io::IntoRawFd;
+use std::os::unix::net::UnixStream;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::{Mutex, Once};
+
+static CLEANUPS: Mutex<Vec<fn()>> = Mutex::new(Vec::new());
+
+/// Calls `f` if we get SIGINT or SIGTERM, before dying of the signal.  The
+/// functions are called in the order they were added.
+pub fn on_interrupt(f: fn()) {
+    static INIT: Once = Once::new();
+    INIT.call_once(install);
+    CLEANUPS.lock().unwrap().push(f);
+}
+
+/// Signal handlers can't do much, so ours just pokes a thread (through a
+/// socket), which does the cleaning up and then re-raises the signal
+fn install() {
+    static PIPE: AtomicI32 = AtomicI32::new(-1);
+    extern "C" fn handler(signal: libc::c_int) {
+        let byte = signal as u8;
+        let fd = PIPE.load(Ordering::Relaxed);
+        unsafe { libc::write(fd, &byte as *const u8 as *const libc::c_void, 1) };
+    }
+    // Not pipe2(), which macOS doesn't have.  std makes these close-on-exec.
+    let (mut rx, tx) = match UnixStream::pair() {
+        Ok(x) => x,
+        Err(e) => {
+            eprintln!(
+                "Warning: Couldn't create a socket, so we won't be able to clean up if \
+                 we're interrupted: {}",
+                e
+            );
+            return;
+        }
+    };
+    PIPE.store(tx.into_raw_fd(), Ordering::Relaxed);
+    std::thread::spawn(move || {
+        let mut byte = [0u8];
+        if rx.read_exact(&mut byte).is_err() {
+            return;
+        }
+        let [byte] = byte;
+        let cleanups = CLEANUPS.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        for f in cleanups {
+            f();
+        }
+        unsafe {
+            libc::signal(byte as libc::c_int, libc::SIG_DFL);
+            libc::raise(byte as libc::c_int);
+        }
+    });
+    let handler = handler as extern "C" fn(libc::c_int);
+    for signal in [libc::SIGINT, libc::SIGTERM] {
+        unsafe { libc::signal(signal, handler as libc::sighandler_t) };
+    }
+}
@@ -2,41 +2,52 @@ use ansi_term::{Color, Style};
 use arc_swap::ArcSwap;
 use serde::{Serialize, Serializer};
 use std::fmt;
-use std::sync::{LazyLock, OnceLock};
+use std::sync::LazyLock;
 
 static BENCH_CACHE: LazyLock<ArcSwap<Vec<String>>> = LazyLock::new(ArcSwap::default);
 
+/// Returns the position of `x` in `cache`, adding it if necessary
+fn intern(cache: &ArcSwap<Vec<String>>, x: &str) -> usize {
+    match cache.load().iter().position(|y| x == y) {
+        Some(x) => x,
+        None => {
+            let old = cache.rcu(|cache| {
+                let mut cache = Vec::clone(cache);
+                cache.push(x.to_string());
+                cache
+            });
+            old.len()
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, PartialOrd, Ord, Eq, Copy)]
 pub struct Bench(pub usize);
 
 impl From<&str> for Bench {
     fn from(x: &str) -> Bench {
-        match BENCH_CACHE.load().iter().position(|y| x == y) {
-            Some(x) => Bench(x),
-            None => {
-                let old = BENCH_CACHE.rcu(|cache| {
-                    let mut cache = Vec::clone(cache);
-                    cache.push(x.to_string());
-                    cache
-                });
-                Bench(old.len())
-            }
-        }
+        Bench(intern(&BENCH_CACHE, x))
     }
 }
 
-impl fmt::Display for Bench {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let color = match self.0 % 4 {
+impl Bench {
+    /// Benchmarks are coloured according to the order they were first seen
+    fn color(self) -> Color {
+        match self.0 % 4 {
             0 => Color::Purple,
             1 => Color::Yellow,
             2 => Color::Cyan,
             3 => Color::Green,
             _ => unreachable!(),
-        };
+        }
+    }
+}
+
+impl fmt::Display for Bench {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let cache = BENCH_CACHE.load();
         let s = &cache[self.0];
-        write!(f, "{}", Style::new().fg(color).paint(s))
+        write!(f, "{}", Style::new().fg(self.color()).paint(s))
     }
 }
 
@@ -55,17 +66,20 @@ pub fn all_benches() -> impl Iterator<Item = Bench> {
     (0..BENCH_CACHE.load().len()).map(Bench)
 }
 
-static METRIC_CACHE: OnceLock<Vec<String>> = OnceLock::new();
-
-pub fn init_metrics(metrics: Vec<String>) {
-    METRIC_CACHE.set(metrics).unwrap()
-}
+static METRIC_CACHE: LazyLock<ArcSwap<Vec<String>>> = LazyLock::new(ArcSwap::default);
 
 #[derive(Debug, PartialEq, Clone, PartialOrd, Ord, Eq, Copy)]
 pub struct Metric(pub usize);
+
+impl From<&str> for Metric {
+    fn from(x: &str) -> Metric {
+        Metric(intern(&METRIC_CACHE, x))
+    }
+}
+
 impl fmt::Display for Metric {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let cache = METRIC_CACHE.get().unwrap();
+        let cache = METRIC_CACHE.load();
         f.write_str(&cache[self.0])
     }
 }
@@ -75,14 +89,15 @@ impl Serialize for Metric {
     where
         S: Serializer,
     {
-        let cache = METRIC_CACHE.get().unwrap();
+        let cache = METRIC_CACHE.load();
         let s = &cache[self.0];
         serializer.serialize_str(s)
     }
 }
 
+/// All the metrics seen so far.  More may appear later on.
 pub fn all_metrics() -> impl Iterator<Item = Metric> {
-    (0..METRIC_CACHE.get().unwrap().len()).map(Metric)
+    (0..METRIC_CACHE.load().len()).map(Metric)
 }
 
 #[cfg(test)]
@@ -91,28 +106,27 @@ mod tests {
 
     #[test]
     fn test_bench_roundtrip() {
-        assert_eq!(
-            Bench::from("foobar").to_string(),
-            "\u{1b}[35mfoobar\u{1b}[0m"
-        );
-        assert_eq!(
-            Bench::from("barqux").to_string(),
-            "\u{1b}[33mbarqux\u{1b}[0m"
-        );
-        assert_eq!(
-            Bench::from("barqux").to_string(),
-            "\u{1b}[33mbarqux\u{1b}[0m"
-        );
-        assert_eq!(
-            Bench::from("foobar").to_string(),
-            "\u{1b}[35mfoobar\u{1b}[0m"
-        );
+        // Other tests intern labels too, so we can't know which indices
+        // these will get
+        let foobar = Bench::from("foobar");
+        let barqux = Bench::from("barqux");
+        assert_ne!(foobar, barqux);
+        assert_eq!(Bench::from("foobar"), foobar);
+        assert_eq!(Bench::from("barqux"), barqux);
+        let painted = |x: Bench, s: &str| Style::new().fg(x.color()).paint(s).to_string();
+        assert_eq!(foobar.to_string(), painted(foobar, "foobar"));
+        assert_eq!(barqux.to_string(), painted(barqux, "barqux"));
+        assert_eq!(Bench(0).color(), Color::Purple);
+        assert_eq!(Bench(5).color(), Color::Yellow);
     }
 
     #[test]
     fn test_metric_roundtrip() {
-        init_metrics(vec!["foobar".into(), "barqux".into()]);
-        assert_eq!(Metric(0).to_string(), "foobar");
-        assert_eq!(Metric(1).to_string(), "barqux");
+        let foobar = Metric::from("foobar");
+        let barqux = Metric::from("barqux");
+        assert_ne!(foobar, barqux);
+        assert_eq!(Metric::from("foobar"), foobar);
+        assert_eq!(foobar.to_string(), "foobar");
+        assert_eq!(barqux.to_string(), "barqux");
     }
 }
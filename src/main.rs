@@ -1,17 +1,30 @@
 mod analyze;
+mod cgroup;
+mod config;
+mod cpus;
+mod diff;
+mod extract;
+mod git;
+mod input;
+mod interrupt;
 mod label;
 mod plot;
+mod preamble;
 mod pretty;
 mod sample;
+mod schedule;
+mod server;
 
 use bpaf::Bpaf;
 
 /// Tools for comparative benchmarking
 #[derive(Bpaf)]
 #[bpaf(options, fallback_to_usage)]
+#[allow(clippy::large_enum_variant)] // It's only constructed once
 enum Subcommand {
     Sample(#[bpaf(external(sample::options))] sample::Options),
     Analyze(#[bpaf(external(analyze::options))] analyze::Options),
+    Diff(#[bpaf(external(diff::options))] diff::Options),
     Plot(#[bpaf(external(plot::options))] plot::Options),
 }
 
@@ -23,6 +36,7 @@ fn main() {
     let result = match subcommand().run() {
         Subcommand::Sample(opts) => sample::sample(opts),
         Subcommand::Analyze(opts) => analyze::analyze(opts),
+        Subcommand::Diff(opts) => diff::diff(opts),
         Subcommand::Plot(opts) => plot::plot(opts),
     };
     match result {
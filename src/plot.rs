@@ -1,8 +1,9 @@
+use crate::input;
 use crate::label::*;
 use anyhow::Result;
 use bpaf::Bpaf;
-use log::*;
 use serde_json::json;
+use std::io::Read;
 
 /// Takes CSV data on stdin and produces a vega-lite plot specification on stdout
 #[derive(Bpaf)]
@@ -11,14 +12,18 @@ pub struct Options {
     omit_data: bool,
 }
 
-pub fn mk_chart(metric: Metric) -> serde_json::Value {
+pub fn mk_chart(metric: Metric, unit: Option<&String>) -> serde_json::Value {
     let metric = metric.to_string();
+    let title = match unit {
+        Some(unit) => format!("{} ({})", metric, unit),
+        None => metric.clone(),
+    };
 
     // It's a bit hacky, but we special-case any metrics with these well-known
     // names and optimize their chart.
     if metric == "user_time" || metric == "sys_time" {
         json!({
-            "title": metric,
+            "title": title,
             "width": 640,
             "height": 180,
             "mark": {
@@ -34,7 +39,7 @@ pub fn mk_chart(metric: Metric) -> serde_json::Value {
         })
     } else {
         json!({
-            "title": metric,
+            "title": title,
             "width": 640,
             "height": 180,
             "mark": {
@@ -59,42 +64,79 @@ pub fn mk_chart(metric: Metric) -> serde_json::Value {
 }
 
 pub fn plot(opts: Options) -> Result<()> {
-    let mut rdr = csv::Reader::from_reader(std::io::stdin());
-    let mut headers = rdr.headers().unwrap().into_iter();
-    let benchcol = headers.next().unwrap().to_string();
-    info!("Assuming \"{}\" column is the benchmark name", benchcol);
-    init_metrics(headers.map(|x| x.to_string()).collect());
+    let rdr = input::Reader::new(std::io::stdin())?;
+    println!("{}", spec(rdr, opts.omit_data)?);
+    Ok(())
+}
+
+fn spec(rdr: input::Reader<impl Read>, omit_data: bool) -> Result<serde_json::Value> {
+    let benchcol = rdr.bench_column().to_string();
+    let units = rdr.preamble().units.clone();
+
+    // We read the rows even if we're going to omit them, since the metrics
+    // may not all be in the first header (and in the long format, none are)
+    let mut data = vec![];
+    for row in rdr.rows() {
+        let row = row?;
+        if omit_data || !row.succeeded {
+            continue;
+        }
+        let mut map = serde_json::Map::<String, serde_json::Value>::new();
+        map.insert(benchcol.clone(), json!(row.bench));
+        for (x, y) in row.values {
+            map.insert(x.to_string(), json!(y));
+        }
+        data.push(map);
+    }
 
-    let mut charts = all_metrics().map(mk_chart).collect::<Vec<_>>();
+    // The data may have introduced new metrics, so we do this afterwards
+    let mut charts = all_metrics()
+        .map(|metric| mk_chart(metric, units.get(&metric.to_string())))
+        .collect::<Vec<_>>();
     charts.reverse();
 
-    let plot = if opts.omit_data {
+    let plot = if omit_data {
         json!({
             "$schema": "https://vega.github.io/schema/vega-lite/v4.json",
             "vconcat": charts,
         })
     } else {
-        let data = rdr
-            .into_records()
-            .map(|row| {
-                let row = row.unwrap();
-                let mut row = row.into_iter();
-                let bench = Bench::from(row.next().unwrap());
-                let mut map = serde_json::Map::<String, serde_json::Value>::new();
-                map.insert(benchcol.clone(), json!(bench));
-                for (x, y) in all_metrics().zip(row) {
-                    map.insert(x.to_string(), json!(y.parse::<f64>().unwrap()));
-                }
-                map
-            })
-            .collect::<Vec<_>>();
         json!({
             "$schema": "https://vega.github.io/schema/vega-lite/v4.json",
             "data": { "values": data },
             "vconcat": charts,
         })
     };
+    Ok(plot)
+}
 
-    println!("{}", plot);
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_long_omit_data() {
+        let csv = "# seed: 1
+run_id,benchmark,metric,value
+1,foobar,plot_a,1
+1,foobar,plot_b,2
+2,barqux,plot_a,3
+";
+        let charts = |omit_data| {
+            let rdr = input::Reader::new(csv.as_bytes()).unwrap();
+            let spec = spec(rdr, omit_data).unwrap();
+            let charts = spec["vconcat"].as_array().unwrap();
+            let titles = charts
+                .iter()
+                .map(|x| x["title"].as_str().unwrap().to_string());
+            (titles.collect::<Vec<_>>(), spec.get("data").is_some())
+        };
+        let (titles, has_data) = charts(true);
+        assert!(!has_data);
+        assert!(titles.contains(&"plot_a".to_string()), "{:?}", titles);
+        assert!(titles.contains(&"plot_b".to_string()), "{:?}", titles);
+        let (titles, has_data) = charts(false);
+        assert!(has_data);
+        assert!(titles.contains(&"plot_b".to_string()), "{:?}", titles);
+    }
 }
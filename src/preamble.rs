@@ -0,0 +1,112 @@
+use anyhow::Result;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io::{BufRead, Write};
+
+/// Metadata which `cbdr sample` writes as `#`-prefixed lines before the CSV
+/// header, eg.
+///
+/// ```text
+/// # cbdr-version: 0.2.4
+/// # seed: 1234
+/// # benchmark: {"label":"md5","command":"md5sum big_file"}
+/// # units: {"wall_clock_millis":"ms"}
+/// ```
+///
+/// Tools which don't know about it can treat these lines as comments.
+#[derive(Default, Debug, PartialEq)]
+pub struct Preamble {
+    /// Free-form information about the session, eg. the seed
+    pub info: Vec<(String, String)>,
+    /// In the order they were given to `cbdr sample`
+    pub benchmarks: Vec<BenchInfo>,
+    /// The units of each metric, where known
+    pub units: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct BenchInfo {
+    pub label: String,
+    pub command: String,
+}
+
+impl Preamble {
+    pub fn write(&self, mut out: impl Write) -> Result<()> {
+        for (key, value) in &self.info {
+            writeln!(out, "# {}: {}", key, value)?;
+        }
+        for bench in &self.benchmarks {
+            writeln!(out, "# benchmark: {}", serde_json::to_string(bench)?)?;
+        }
+        if !self.units.is_empty() {
+            writeln!(out, "# units: {}", serde_json::to_string(&self.units)?)?;
+        }
+        Ok(())
+    }
+
+    /// Consumes the `#`-prefixed lines at the start of the input.  Any other
+    /// comments are kept in `info`.
+    pub fn read(rdr: &mut impl BufRead) -> Result<Preamble> {
+        let mut preamble = Preamble::default();
+        let mut line = String::new();
+        while rdr.fill_buf()?.starts_with(b"#") {
+            line.clear();
+            rdr.read_line(&mut line)?;
+            let line = line.trim_start_matches('#').trim();
+            let (key, value) = line.split_once(':').unwrap_or(("", line));
+            let value = value.trim();
+            match key {
+                "benchmark" => preamble.benchmarks.push(serde_json::from_str(value)?),
+                "units" => preamble.units = serde_json::from_str(value)?,
+                _ => preamble.info.push((key.to_string(), value.to_string())),
+            }
+        }
+        debug!("Read preamble: {:?}", preamble);
+        Ok(preamble)
+    }
+}
+
+/// A description of the machine we're running on
+pub fn host_info() -> String {
+    let cpus = std::thread::available_parallelism().map_or(0, |x| x.get());
+    let mut uts = unsafe { std::mem::zeroed::<libc::utsname>() };
+    if unsafe { libc::uname(&mut uts) } != 0 {
+        return format!("unknown ({} CPUs)", cpus);
+    }
+    let field = |x: &[libc::c_char]| {
+        let bytes = x.iter().take_while(|&&c| c != 0).map(|&c| c as u8);
+        String::from_utf8_lossy(&bytes.collect::<Vec<_>>()).into_owned()
+    };
+    format!(
+        "{} ({} {} {}, {} CPUs)",
+        field(&uts.nodename),
+        field(&uts.sysname),
+        field(&uts.release),
+        field(&uts.machine),
+        cpus
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_roundtrip() {
+        let preamble = Preamble {
+            info: vec![("seed".into(), "42".into())],
+            benchmarks: vec![BenchInfo {
+                label: "a:b".into(),
+                command: "echo \"hi\"".into(),
+            }],
+            units: [("wall_clock_millis".into(), "ms".into())].into(),
+        };
+        let mut buf = vec![];
+        preamble.write(&mut buf).unwrap();
+        buf.extend(b"benchmark,x\n");
+        let mut rdr = &buf[..];
+        assert_eq!(Preamble::read(&mut rdr).unwrap(), preamble);
+        assert_eq!(rdr, b"benchmark,x\n");
+    }
+}
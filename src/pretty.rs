@@ -20,13 +20,9 @@ pub fn render(
             first = false;
         }
         writeln!(out, "\t{}\t{}\tdifference ({}% CI)", from, to, significance)?;
-        let from_stats = measurements.bench_stats(from);
-        let to_stats = measurements.bench_stats(to);
-        for (((metric, from), to), ci) in all_metrics()
-            .zip(from_stats.iter())
-            .zip(to_stats.iter())
-            .zip(diff.iter())
-        {
+        for (metric, ci) in all_metrics().zip(diff.iter()) {
+            let from = measurements.stats(from, metric);
+            let to = measurements.stats(to, metric);
             writeln!(
                 out,
                 "{}\t{:.3} ± {:.3}\t{:.3} ± {:.3}\t{}",
@@ -41,8 +37,8 @@ pub fn render(
         writeln!(
             out,
             "samples\t{}\t{}",
-            from_stats[0].count(),
-            to_stats[0].count()
+            measurements.runs(from),
+            measurements.runs(to)
         )?;
     }
 
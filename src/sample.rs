@@ -1,18 +1,69 @@
+use crate::analyze::Measurements;
+use crate::cgroup::CgroupRoot;
+use crate::config::Config;
+use crate::cpus::CpuList;
+use crate::extract::{self, Extractor};
+use crate::git::{self, Worktree};
+use crate::input;
+use crate::interrupt;
+use crate::label::*;
+use crate::preamble::{self, BenchInfo, Preamble};
+use crate::schedule::*;
+use crate::server::{self, Reply, Server};
 use anyhow::{bail, Context, Result};
 use bpaf::Bpaf;
 use std::collections::{BTreeMap, BTreeSet};
 use std::convert::Infallible;
 use std::fmt;
-use std::io::Write;
-use std::process::{Command, Stdio};
+use std::fs::File;
+use std::io::{LineWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use time_cmd::*;
 
-/// Repeatedly runs benchmarks chosen at random and prints results as CSV
+/// Repeatedly runs benchmarks in a random order and prints results as CSV
 #[derive(Bpaf)]
 #[bpaf(command("sample"))]
 pub struct Options {
+    /// Read benchmarks and settings from this TOML file.  Settings given on
+    /// the command line take precedence.
+    #[bpaf(long, argument("PATH"))]
+    pub config: Option<PathBuf>,
+    /// Write the results to this file instead of stdout
+    #[bpaf(long, short, argument("PATH"))]
+    pub output: Option<PathBuf>,
+    /// Add the results to the end of this file, which should have been
+    /// written by an earlier `cbdr sample` with the same benchmarks and
+    /// options.  If it doesn't exist yet (or has no CSV header), it's
+    /// created afresh.
+    #[bpaf(long, argument("PATH"))]
+    pub append: Option<PathBuf>,
+    /// "wide" (one row per run, one column per metric) or "long" (one row
+    /// per measurement).  Defaults to "wide".
+    #[bpaf(long, argument("FORMAT"))]
+    pub format: Option<Format>,
+    /// Add columns saying when and where each run happened: run_id, round,
+    /// worker, start_unix_ns, and end_unix_ns
+    #[bpaf(long)]
+    pub run_metadata: bool,
+    /// Compare this git revision against --git-head.  Each is checked out in
+    /// a temporary `git worktree`, where --run is run.  The targets are
+    /// labeled with their short SHAs.
+    #[bpaf(long, argument("REV"))]
+    pub git_base: Option<String>,
+    /// The revision to compare against --git-base.  Defaults to "HEAD".
+    #[bpaf(long, argument("REV"))]
+    pub git_head: Option<String>,
+    /// With --git-base: a command to run once in each worktree before
+    /// sampling starts, eg. "cargo build --release".  It isn't timed.
+    #[bpaf(long, argument("CMD"))]
+    pub build: Option<String>,
+    /// With --git-base: the benchmark to run in each worktree
+    #[bpaf(long, argument("CMD"))]
+    pub run: Option<String>,
     /// A benchmark script to use.  Labels will be passed as $1
     #[bpaf(long, short, argument("PATH"))]
     pub bench: Option<String>,
@@ -20,10 +71,127 @@ pub struct Options {
     #[bpaf(long, short)]
     #[bpaf(argument("PATH"))]
     pub scripts: Vec<NamedString>,
+    /// These benchmarks will be started once, in a shell, and kept running.
+    /// Each time a measurement is needed, "run" is written to its stdin, and
+    /// it should reply with a line of JSON on stdout.
+    #[bpaf(long("server"), argument("CMD"))]
+    pub servers: Vec<NamedString>,
+    /// Run each benchmark this many times before recording anything.
+    /// Defaults to 1, which is also the minimum: the warm-up is when
+    /// --threshold-metric and --counters are checked.
+    #[bpaf(long, argument("N"))]
+    pub warmup: Option<usize>,
+    /// After the --warmup runs, carry on warming up until the coefficient of
+    /// variation of the last --warmup-window runs is below this percentage.
+    /// Gives up (with a warning) after 1000 runs.
+    #[bpaf(long, argument("PERCENT"))]
+    pub warmup_until_stable: Option<f64>,
+    /// The number of runs --warmup-until-stable looks at.  Defaults to 10.
+    #[bpaf(long, argument("K"))]
+    pub warmup_window: Option<usize>,
+    /// The metric --warmup-until-stable looks at.  Defaults to
+    /// "wall_clock_millis".
+    #[bpaf(long, argument("NAME"))]
+    pub warmup_metric: Option<String>,
+    /// Write the warm-up runs to the output too, with "warmup" in a "phase"
+    /// column.  `cbdr analyze` ignores them.
+    #[bpaf(long)]
+    pub keep_warmup: bool,
     /// Automatically exit after this length of time has elapsed.
     /// Takes free-form input, eg. "1m20s".
     #[bpaf(long, short, argument("DURATION"))]
     pub timeout: Option<humantime::Duration>,
+    /// Automatically exit once the CI of every difference is narrower than
+    /// this.  Units are percent of base.  --timeout still applies.
+    #[bpaf(long, argument("PERCENT"))]
+    pub threshold: Option<f64>,
+    /// The metric --threshold looks at.  Defaults to "wall_clock_millis" if
+    /// the benchmarks report it, or every metric if they don't.
+    #[bpaf(long, argument("NAME"))]
+    pub threshold_metric: Option<String>,
+    /// The significance level of the confidence intervals used by
+    /// --threshold.  Defaults to 95.
+    #[bpaf(long, argument("P-VALUE"))]
+    pub significance: Option<f64>,
+    /// Kill any run which takes longer than this, along with any processes
+    /// it spawned.  Takes free-form input, eg. "30s".
+    #[bpaf(long, argument("DURATION"))]
+    pub run_timeout: Option<humantime::Duration>,
+    /// What to do when a run times out: "abort", "skip", or "record".
+    /// Defaults to "abort".
+    #[bpaf(long, argument("POLICY"))]
+    pub on_timeout: Option<Policy>,
+    /// How to choose which benchmark to run next: "random",
+    /// "shuffled-rounds" (each benchmark once per round, in a random order),
+    /// "abba" (each benchmark in order, then in reverse), or "round-robin".
+    /// Defaults to "random".
+    #[bpaf(long, argument("SCHEDULE"))]
+    pub schedule: Option<Schedule>,
+    /// Seed the random number generator which chooses the benchmarks.  If
+    /// not given, a seed is chosen at random.  Either way, it's printed.
+    #[bpaf(long, argument("SEED"))]
+    pub seed: Option<u64>,
+    /// If this file exists, run benchmarks in the sequence recorded in it
+    /// (stopping when it runs out).  If not, record the sequence to it.
+    #[bpaf(long, argument("PATH"))]
+    pub schedule_file: Option<PathBuf>,
+    /// Run this many benchmarks in parallel.  Defaults to 1.
+    #[bpaf(long, short('j'), argument("N"))]
+    pub jobs: Option<usize>,
+    /// Pin the workers to these CPUs, eg. "0-15".  The CPUs are shared out
+    /// between the workers, so each gets a disjoint set.
+    #[bpaf(long, argument("CPUS"))]
+    pub cpus: Option<CpuList>,
+    /// What to do when a run exits non-zero: "abort", "skip", or "record".
+    /// Recorded runs have NaN for all metrics, and the exit_status and signal
+    /// columns say what happened.  Defaults to "abort".
+    #[bpaf(long, argument("POLICY"))]
+    pub on_failure: Option<Policy>,
+    /// A command to run before each run of a benchmark.  It isn't timed.
+    /// "--prepare=foo" applies to all benchmarks; "--prepare=name:foo" only
+    /// applies to the benchmark called "name".
+    #[bpaf(long, argument("CMD"))]
+    pub prepare: Vec<NamedString>,
+    /// A command to run after each run of a benchmark.  It isn't timed.
+    /// "--cleanup=foo" applies to all benchmarks; "--cleanup=name:foo" only
+    /// applies to the benchmark called "name".
+    #[bpaf(long, argument("CMD"))]
+    pub cleanup: Vec<NamedString>,
+    /// The directory to run benchmarks (and their hooks) in.
+    /// "--cwd=dir" applies to all benchmarks (except the ones from
+    /// --git-base, which run in their worktrees); "--cwd=name:dir" only
+    /// applies to the benchmark called "name".
+    #[bpaf(long, argument("DIR"))]
+    pub cwd: Vec<NamedString>,
+    /// Set an environment variable for benchmarks (and their hooks).
+    /// "--env=KEY=VALUE" applies to all benchmarks; "--env=name:KEY=VALUE"
+    /// only applies to the benchmark called "name".
+    #[bpaf(long, argument("KEY=VALUE"))]
+    pub env: Vec<NamedString>,
+    /// Don't pass cbdr's environment on to the benchmarks; they only get the
+    /// variables set with --env.  Note that this includes PATH.
+    #[bpaf(long)]
+    pub clear_env: bool,
+    /// Extract a metric from the output of each run, eg.
+    /// "rps=processed (\S+) req/s".  The value is the regex's first capture
+    /// group.  Only applies to plain commands (not scripts or servers).
+    #[bpaf(long("metric"), argument("NAME=REGEX"))]
+    pub metrics: Vec<Extractor>,
+    /// Collect these performance counters for each run, eg.
+    /// "instructions,cycles", or "all".  Available counters: instructions,
+    /// cycles, branches, branch-misses, task-clock, context-switches, and
+    /// page-faults.  Linux only.  Only applies to plain commands (not
+    /// scripts or servers).
+    #[bpaf(long, argument("COUNTERS"))]
+    pub counters: Option<Counters>,
+    /// Run each run in a fresh cgroup under this cgroup v2 directory, and
+    /// record its CPU time, peak memory, I/O, and peak number of processes.
+    /// Anything still running in the cgroup when the run ends is killed.
+    /// Moving the process into its cgroup adds some overhead to
+    /// wall_clock_millis.  Only applies to plain commands (not scripts or
+    /// servers).
+    #[bpaf(long, argument("PATH"))]
+    pub cgroup: Option<PathBuf>,
     /// A target labeled "before".  "--before=foo" is equivalent to "before:foo".
     #[bpaf(argument("BENCH"))]
     pub before: Option<String>,
@@ -49,6 +217,94 @@ impl FromStr for NamedString {
     }
 }
 
+/// What to do when a run doesn't complete
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Policy {
+    /// Stop sampling and exit with an error
+    Abort,
+    /// Discard the run and carry on sampling
+    Skip,
+    /// Write a row for the run (with no measurements) and carry on sampling
+    Record,
+}
+impl FromStr for Policy {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Policy> {
+        match x {
+            "abort" => Ok(Policy::Abort),
+            "skip" => Ok(Policy::Skip),
+            "record" => Ok(Policy::Record),
+            _ => bail!("Expected \"abort\", \"skip\", or \"record\""),
+        }
+    }
+}
+impl fmt::Display for Policy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Policy::Abort => f.write_str("abort"),
+            Policy::Skip => f.write_str("skip"),
+            Policy::Record => f.write_str("record"),
+        }
+    }
+}
+
+/// The shape of the CSV written by `cbdr sample`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Format {
+    /// One row per run, with a column for each metric
+    Wide,
+    /// One row per measurement: run_id, benchmark, metric, value
+    Long,
+}
+impl FromStr for Format {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Format> {
+        match x {
+            "wide" => Ok(Format::Wide),
+            "long" => Ok(Format::Long),
+            _ => bail!("Expected \"wide\" or \"long\""),
+        }
+    }
+}
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Format::Wide => f.write_str("wide"),
+            Format::Long => f.write_str("long"),
+        }
+    }
+}
+
+/// A list of performance counters, as given to --counters
+#[derive(Clone, PartialEq, Debug)]
+pub struct Counters(pub Vec<Counter>);
+impl FromStr for Counters {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Counters> {
+        if x == "all" {
+            return Ok(Counters(Counter::ALL.to_vec()));
+        }
+        let counters = x
+            .split(',')
+            .map(|x| x.trim().parse())
+            .collect::<Result<_, _>>()?;
+        Ok(Counters(counters))
+    }
+}
+
+/// The name of the metric we record for a performance counter
+fn counter_metric(counter: Counter) -> &'static str {
+    match counter {
+        Counter::Instructions => "instructions",
+        Counter::Cycles => "cycles",
+        Counter::Branches => "branches",
+        Counter::BranchMisses => "branch_misses",
+        Counter::TaskClock => "task_clock_millis",
+        Counter::ContextSwitches => "context_switches",
+        Counter::PageFaults => "page_faults",
+    }
+}
+
 impl Options {
     fn targets(&self) -> impl Iterator<Item = NamedString> + '_ {
         self.targets
@@ -65,101 +321,1240 @@ impl Options {
                     .map(|rest| NamedString(Some("after".into()), rest.clone())),
             )
     }
-    fn benchmarks(self) -> Vec<Benchmark> {
-        let mut benches = self
-            .scripts
-            .iter()
-            .cloned()
-            .map(|NamedString(name, rest)| Benchmark {
-                name,
-                runner: BenchRunner::Script(rest, vec![]),
+    /// The benchmarks from the config file come first, followed by the ones
+    /// given on the command line
+    fn benchmarks(&self, config: Config) -> Result<Vec<Benchmark>> {
+        let names = config.targets.iter().map(|x| x.name.clone());
+        let prepare = with_config(
+            config.prepare,
+            names
+                .clone()
+                .zip(config.targets.iter().map(|x| x.prepare.clone())),
+            &self.prepare,
+        );
+        let cleanup = with_config(
+            config.cleanup,
+            names.zip(config.targets.iter().map(|x| x.cleanup.clone())),
+            &self.cleanup,
+        );
+        let mut benches = config
+            .targets
+            .into_iter()
+            .map(|target| {
+                let runner = match (target.command, target.script) {
+                    (Some(cmd), _) => BenchRunner::Prog(cmd),
+                    (None, Some(script)) => BenchRunner::Script(script, target.args),
+                    (None, None) => match target.server {
+                        Some(cmd) => BenchRunner::Server(Server::new(cmd)),
+                        None => unreachable!("checked by Config::load()"),
+                    },
+                };
+                Benchmark {
+                    name: Some(target.name),
+                    runner,
+                    prepare: None,
+                    cleanup: None,
+                    extractors: vec![],
+                    counters: vec![],
+                    cgroup: None,
+                    cwd: target.cwd,
+                    env: target.env,
+                    clear_env: target.clear_env,
+                    worktree: None,
+                }
             })
             .collect::<Vec<_>>();
+        benches.extend(self.scripts.iter().cloned().map(|NamedString(name, rest)| {
+            Benchmark::new(name, BenchRunner::Script(rest, vec![]))
+        }));
+        benches.extend(self.servers.iter().cloned().map(|NamedString(name, rest)| {
+            Benchmark::new(name, BenchRunner::Server(Server::new(rest)))
+        }));
+        let git_base = self.git_base.as_ref().or(config.git_base.as_ref());
+        let git_head = self.git_head.as_ref().or(config.git_head.as_ref());
+        let run = self.run.as_ref().or(config.run.as_ref());
+        let build = self.build.as_ref().or(config.build.as_ref());
+        match (git_base, run) {
+            (Some(base), Some(run)) => {
+                let base = git::resolve(base)?;
+                let head = git::resolve(git_head.map_or("HEAD", |x| x.as_str()))?;
+                if base == head {
+                    bail!("--git-base and --git-head are the same commit ({})", base);
+                }
+                for sha in [base, head] {
+                    let worktree = Worktree::new(&sha)?;
+                    let name = worktree.short_sha.clone();
+                    let mut bench = Benchmark::new(Some(name), BenchRunner::Prog(run.clone()));
+                    bench.cwd = Some(worktree.path.clone());
+                    bench.worktree = Some(worktree);
+                    benches.push(bench);
+                }
+            }
+            (Some(_), None) => bail!("--git-base requires --run"),
+            (None, _) if git_head.is_some() || run.is_some() || build.is_some() => {
+                bail!("--git-head, --build, and --run require --git-base")
+            }
+            (None, _) => (),
+        }
         if let Some(bench) = self.bench.as_ref() {
-            benches.extend(self.targets().map(|NamedString(name, rest)| Benchmark {
-                name,
-                runner: BenchRunner::Script(bench.clone(), vec![rest]),
+            benches.extend(self.targets().map(|NamedString(name, rest)| {
+                Benchmark::new(name, BenchRunner::Script(bench.clone(), vec![rest]))
             }));
         } else {
-            benches.extend(self.targets().map(|NamedString(name, rest)| Benchmark {
-                name,
-                runner: BenchRunner::Prog(rest),
-            }));
+            benches.extend(
+                self.targets()
+                    .map(|NamedString(name, rest)| Benchmark::new(name, BenchRunner::Prog(rest))),
+            );
+        }
+        apply(&mut benches, &prepare, "--prepare", |bench, cmd| {
+            bench.prepare = Some(cmd.to_string())
+        })?;
+        apply(&mut benches, &cleanup, "--cleanup", |bench, cmd| {
+            bench.cleanup = Some(cmd.to_string())
+        })?;
+        // The --git-base benchmarks have to run in their worktrees
+        let in_worktree = |name: &str| {
+            benches
+                .iter()
+                .any(|x| x.worktree.is_some() && x.to_string() == name)
+        };
+        if let Some(name) = self
+            .cwd
+            .iter()
+            .filter_map(|x| x.0.as_ref())
+            .find(|x| in_worktree(x))
+        {
+            bail!("--cwd: \"{}\" has to run in its git worktree", name);
+        }
+        apply(&mut benches, &self.cwd, "--cwd", |bench, dir| {
+            if bench.worktree.is_none() {
+                bench.cwd = Some(dir.into())
+            }
+        })?;
+        // "--env=PATH=/bin:/usr/bin" isn't for a benchmark called
+        // "PATH=/bin", even though it has a colon in it
+        let env = self
+            .env
+            .iter()
+            .map(|x| match x {
+                NamedString(Some(name), rest) if name.contains('=') => {
+                    NamedString(None, format!("{}:{}", name, rest))
+                }
+                x => x.clone(),
+            })
+            .collect::<Vec<_>>();
+        if let Some(NamedString(_, x)) = env.iter().find(|x| !x.1.contains('=')) {
+            bail!("--env: Expected KEY=VALUE, but got \"{}\"", x);
+        }
+        apply(&mut benches, &env, "--env", |bench, var| {
+            let (key, value) = var.split_once('=').unwrap();
+            bench.env.insert(key.into(), value.into());
+        })?;
+        if self.clear_env || config.clear_env {
+            for bench in &mut benches {
+                bench.clear_env = true;
+            }
+        }
+        let mut extractors = config
+            .metrics
+            .iter()
+            .map(|(name, regex)| Extractor::new(name, regex))
+            .collect::<Result<Vec<_>>>()?;
+        extractors.extend(self.metrics.iter().cloned());
+        let counters = self.counters.as_ref().or(config.counters.as_ref());
+        let counters = counters.map_or(vec![], |x| x.0.clone());
+        let cgroup = match self.cgroup.as_ref().or(config.cgroup.as_ref()) {
+            Some(path) => Some(CgroupRoot::new(path)?),
+            None => None,
+        };
+        for bench in &mut benches {
+            bench.extractors = extractors.clone();
+            bench.counters = counters.clone();
+            bench.cgroup = cgroup.clone();
+        }
+        Ok(benches)
+    }
+}
+
+/// Combines a setting given on the command line with the same setting from
+/// the config file, which may have a value for every target as well as
+/// values for particular targets.  The command line's value for every
+/// target replaces the file's, and its values for particular targets come
+/// last, so `apply()` gives them precedence.
+fn with_config(
+    global: Option<String>,
+    targets: impl Iterator<Item = (String, Option<String>)>,
+    cli: &[NamedString],
+) -> Vec<NamedString> {
+    let mut ret = vec![];
+    if !cli.iter().any(|x| x.0.is_none()) {
+        ret.extend(global.map(|x| NamedString(None, x)));
+    }
+    ret.extend(targets.filter_map(|(name, x)| Some(NamedString(Some(name), x?))));
+    ret.extend(cli.iter().cloned());
+    ret
+}
+
+/// Applies settings given as "name:value" (for the benchmark called "name")
+/// or just "value" (for all benchmarks).  Settings for a specific benchmark
+/// take precedence.
+fn apply(
+    benches: &mut [Benchmark],
+    settings: &[NamedString],
+    flag: &str,
+    mut f: impl FnMut(&mut Benchmark, &str),
+) -> Result<()> {
+    for NamedString(_, value) in settings.iter().filter(|x| x.0.is_none()) {
+        for bench in benches.iter_mut() {
+            f(bench, value);
+        }
+    }
+    for NamedString(name, value) in settings {
+        let Some(name) = name else { continue };
+        let mut found = false;
+        for bench in benches.iter_mut().filter(|x| x.to_string() == *name) {
+            f(bench, value);
+            found = true;
+        }
+        if !found {
+            bail!("{}: There's no benchmark called \"{}\"", flag, name);
         }
-        benches
     }
+    Ok(())
 }
 
 pub fn sample(opts: Options) -> Result<()> {
-    let timeout = opts.timeout.map(|x| x.into());
-    let benches = opts.benchmarks();
+    // Benchmarks with --run-timeout, and servers, are in their own process
+    // groups, so they wouldn't get a ctrl-C from the terminal
+    interrupt::on_interrupt(time_cmd::kill_running);
+    interrupt::on_interrupt(server::kill_all);
+    let config = match &opts.config {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    let timeout = opts.timeout.or(config.timeout).map(|x| x.into());
+    let jobs = opts.jobs.or(config.jobs).unwrap_or(1);
+    if jobs == 0 {
+        bail!("--jobs must be at least 1");
+    }
+    let cpus = match opts.cpus.as_ref().or(config.cpus.as_ref()) {
+        Some(cpus) => cpus.split(jobs)?.into_iter().map(Some).collect(),
+        None => vec![None; jobs],
+    };
+    let significance = opts.significance.or(config.significance).unwrap_or(95.);
+    if significance <= 0. || significance >= 100. {
+        bail!("Significance level must be between 0 and 100");
+    }
+    let run_timeout = opts.run_timeout.or(config.run_timeout).map(|x| x.into());
+    let policies = Policies {
+        on_timeout: opts
+            .on_timeout
+            .or(config.on_timeout)
+            .unwrap_or(Policy::Abort),
+        on_failure: opts
+            .on_failure
+            .or(config.on_failure)
+            .unwrap_or(Policy::Abort),
+    };
+    let threshold = opts.threshold.or(config.threshold);
+    let threshold_metric = (opts.threshold_metric.clone()).or(config.threshold_metric.clone());
+    let schedule = opts
+        .schedule
+        .or(config.schedule)
+        .unwrap_or(Schedule::Random);
+    let format = opts.format.or(config.format).unwrap_or(Format::Wide);
+    let run_metadata = opts.run_metadata || config.run_metadata;
+    let warmup = Warmup {
+        runs: opts.warmup.or(config.warmup).unwrap_or(1),
+        until_stable: opts.warmup_until_stable.or(config.warmup_until_stable),
+        window: opts.warmup_window.or(config.warmup_window).unwrap_or(10),
+        metric: (opts.warmup_metric.clone())
+            .or(config.warmup_metric.clone())
+            .unwrap_or_else(|| "wall_clock_millis".into()),
+    };
+    if warmup.runs == 0 {
+        bail!("--warmup must be at least 1");
+    }
+    if warmup.window < 2 {
+        bail!("--warmup-window must be at least 2");
+    }
+    let keep_warmup = opts.keep_warmup || config.keep_warmup;
+    let sig_level = significance / 100.;
+    let seed = opts.seed.or(config.seed).unwrap_or_else(rand::random);
+    let schedule_file = opts.schedule_file.clone().or(config.schedule_file.clone());
+    let output = opts.output.clone().or(config.output.clone());
+    let append = opts.append.clone().or(config.append.clone());
+    let previous = match append.as_ref().filter(|x| x.exists()) {
+        Some(path) => read_previous(path).with_context(|| format!("Reading {}", path.display()))?,
+        None => None,
+    };
+    let mut out: Box<dyn Write + Send> = match (&output, &append) {
+        (Some(_), Some(_)) => bail!("--output and --append can't be used together"),
+        (Some(path), None) => Box::new(LineWriter::new(
+            File::create(path).with_context(|| format!("Creating {}", path.display()))?,
+        )),
+        (None, Some(path)) if previous.is_some() => Box::new(LineWriter::new(
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Opening {}", path.display()))?,
+        )),
+        // There's nothing to carry on from, so start afresh
+        (None, Some(path)) => Box::new(LineWriter::new(
+            File::create(path).with_context(|| format!("Creating {}", path.display()))?,
+        )),
+        (None, None) => Box::new(std::io::stdout()),
+    };
+    let build = opts.build.clone().or(config.build.clone());
+    let benches = opts.benchmarks(config)?;
     if benches.is_empty() {
         bail!("Must specify at least one benchmark");
     }
+    if threshold.is_some() && benches.len() < 2 {
+        bail!("--threshold requires at least two benchmarks");
+    }
+    if let Some(previous) = &previous {
+        let labels = benches.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+        if previous.labels != labels {
+            bail!(
+                "{}: The benchmarks don't match.  The file has {:?}, but we're running {:?}.",
+                append.as_ref().unwrap().display(),
+                previous.labels,
+                labels
+            );
+        }
+    }
 
-    let stats = warm_up(&benches)?;
-    let mut stdout = CsvWriter::new(std::io::stdout(), stats.iter())?;
-    // Run the benches in-order once, so `cbdr analyze` knows the correct order
-    for bench in &benches {
-        let values = run_bench(bench)?;
-        stdout.write_csv(&bench.to_string(), &values)?;
+    let replay = schedule_file.as_ref().filter(|x| x.exists());
+    if let (Some(path), Some(_)) = (replay, &previous) {
+        // We don't know how much of it the earlier session got through
+        // (some of its runs may have been skipped, or never finished)
+        bail!(
+            "--append can't carry on replaying {}.  Use a new --schedule-file to record a \
+             schedule for this session instead.",
+            path.display()
+        );
     }
+    let scheduler = if let Some(path) = replay {
+        eprintln!("Replaying the schedule in {}", path.display());
+        Scheduler::replay(path, benches.len())?
+    } else {
+        eprintln!("Using seed {}", seed);
+        let mut scheduler = Scheduler::new(schedule, benches.len(), seed);
+        if let Some(path) = &schedule_file {
+            scheduler.record_to(path)?;
+        }
+        scheduler
+    };
 
-    let start = timeout.map(|_| Instant::now());
-    let elapsed = || start.map(|s| s.elapsed());
-    while elapsed() <= timeout {
-        let idx = rand::random::<usize>() % benches.len();
-        let bench = &benches[idx];
-        let values = run_bench(bench)?;
-        stdout.write_csv(&bench.to_string(), &values)?;
+    if let Some(cmd) = &build {
+        for bench in benches.iter().filter(|x| x.worktree.is_some()) {
+            eprintln!("Building {}...", bench);
+            let status = bench
+                .command("/bin/sh")
+                .arg("-c")
+                .arg(cmd)
+                // Our stdout is for results
+                .stdout(std::io::stderr())
+                .status()
+                .with_context(|| bench.context("build"))?;
+            if !status.success() {
+                bail!("{}: Build failed ({})", bench, status);
+            }
+        }
+        eprintln!();
     }
-    Ok(())
+    let WarmedUp {
+        stats,
+        runs: warmup_runs,
+        failures,
+    } = warm_up(&benches, run_timeout, policies, &warmup)?;
+    warn_missing_counters(&benches, &stats);
+    let threshold_metric = match threshold_metric {
+        Some(name) if !stats.contains(&name) => {
+            bail!(
+                "--threshold-metric: None of the benchmarks report \"{}\"",
+                name
+            )
+        }
+        Some(name) => Some(Metric::from(name.as_str())),
+        // Counters like voluntary_ctx_switches are noisy and often close
+        // to zero, so their CIs can take forever to narrow
+        None if stats.contains("wall_clock_millis") => Some(Metric::from("wall_clock_millis")),
+        None => None,
+    };
+    let columns = Columns {
+        run_id: run_metadata,
+        round: run_metadata || replay.is_some() || schedule != Schedule::Random,
+        worker: run_metadata || jobs > 1,
+        timestamps: run_metadata,
+        status: policies.on_timeout == Policy::Record || policies.on_failure == Policy::Record,
+        phase: keep_warmup,
+    };
+    let (mut out, previous) = match previous {
+        Some(previous) => {
+            // The preamble is already there.  (We can't be replaying a
+            // schedule; see above.)
+            writeln!(out, "# Resumed with seed {}", seed)?;
+            let path = append.as_ref().unwrap();
+            let out = CsvWriter::append(out, format, columns, &previous.header)
+                .with_context(|| format!("Appending to {}", path.display()))?;
+            (out, previous)
+        }
+        None => {
+            let preamble = new_preamble(&benches, &stats, replay, seed);
+            preamble.write(&mut out)?;
+            let out = CsvWriter::new(out, format, stats.into_iter().collect(), columns)?;
+            (out, Previous::default())
+        }
+    };
+
+    let mut n_started = previous.n_runs;
+    if keep_warmup {
+        for (idx, run) in warmup_runs {
+            let mut info = RunInfo {
+                run_id: 0,
+                round: previous.next_round,
+                worker: 0,
+                span: run.span,
+                warmup: true,
+            };
+            if let RunResult::Done(samples) = &run.result {
+                for values in samples {
+                    n_started += 1;
+                    info.run_id = n_started;
+                    let bench = benches[idx].to_string();
+                    out.write_csv(&bench, &info, &run.result, Some(values))?;
+                }
+            }
+        }
+    }
+
+    // We keep running stats so we know when to stop.  These are the same
+    // pairs which `cbdr analyze` compares by default.
+    let labels = benches
+        .iter()
+        .map(|x| Bench::from(x.to_string().as_str()))
+        .collect::<Vec<_>>();
+    let session = Session {
+        benches,
+        labels,
+        run_timeout,
+        policies,
+        threshold,
+        threshold_metric,
+        sig_level,
+        deadline: timeout.map(|t| Instant::now() + t),
+        first_round: previous.next_round,
+        state: Mutex::new(State {
+            out,
+            n_started,
+            measurements: previous.measurements,
+            failures,
+            finished: false,
+            scheduler,
+        }),
+    };
+
+    let session = &session;
+    let results = std::thread::scope(|scope| {
+        let workers = cpus
+            .into_iter()
+            .enumerate()
+            .map(|(worker, cpus)| {
+                scope.spawn(move || {
+                    if let Some(cpus) = cpus {
+                        cpus.pin_current_thread().with_context(|| {
+                            format!("Pinning worker {} to CPUs {}", worker, cpus)
+                        })?;
+                    }
+                    let ret = session.work(worker);
+                    // If one worker fails, tell the others to stop too
+                    session.state.lock().unwrap().finished = true;
+                    ret
+                })
+            })
+            .collect::<Vec<_>>();
+        workers
+            .into_iter()
+            .map(|x| x.join().expect("worker panicked"))
+            .collect::<Vec<_>>()
+    });
+
+    let state = session.state.lock().unwrap();
+    let failures = &state.failures;
+    if failures.runs > 0 {
+        eprintln!("{} runs failed", failures.runs);
+    }
+    if failures.timeouts > 0 {
+        eprintln!("{} runs timed out", failures.timeouts);
+    }
+    if failures.hooks > 0 {
+        eprintln!("{} prepare/cleanup commands failed", failures.hooks);
+    }
+    results.into_iter().collect()
+}
+
+/// This tells `cbdr analyze` the order of the benchmarks, among other
+/// things
+fn new_preamble(
+    benches: &[Benchmark],
+    stats: &BTreeSet<String>,
+    replay: Option<&PathBuf>,
+    seed: u64,
+) -> Preamble {
+    let mut info = vec![
+        ("cbdr-version".into(), env!("CARGO_PKG_VERSION").into()),
+        ("host".into(), preamble::host_info()),
+    ];
+    match replay {
+        Some(path) => info.push(("schedule-file".into(), path.display().to_string())),
+        None => info.push(("seed".into(), seed.to_string())),
+    }
+    Preamble {
+        info,
+        benchmarks: benches
+            .iter()
+            .map(|x| BenchInfo {
+                label: x.to_string(),
+                command: x.runner.to_string(),
+            })
+            .collect(),
+        units: BUILTIN_UNITS
+            .iter()
+            .filter(|(metric, _)| stats.contains(*metric))
+            .map(|(metric, unit)| (metric.to_string(), unit.to_string()))
+            .collect(),
+    }
+}
+
+/// What we need to know about the results which we're appending to
+#[derive(Default)]
+struct Previous {
+    /// The benchmarks listed in the preamble
+    labels: Vec<String>,
+    /// The last header row
+    header: Vec<String>,
+    /// The highest run ID used so far
+    n_runs: usize,
+    /// The round after the last one recorded
+    next_round: usize,
+    measurements: Measurements,
+}
+
+/// Returns `None` if there's no header yet: a session which was interrupted
+/// during warm-up leaves the file empty (or with only part of a preamble).
+fn read_previous(path: &Path) -> Result<Option<Previous>> {
+    parse_previous(&std::fs::read(path)?)
+}
+
+fn parse_previous(contents: &[u8]) -> Result<Option<Previous>> {
+    let has_header = contents
+        .split(|&x| x == b'\n')
+        .any(|x| !x.trim_ascii().is_empty() && !x.starts_with(b"#"));
+    if !has_header {
+        return Ok(None);
+    }
+    let rdr = input::Reader::new(contents)?;
+    let labels = rdr.preamble().benchmarks.iter().map(|x| x.label.clone());
+    let mut previous = Previous {
+        labels: labels.collect(),
+        ..Previous::default()
+    };
+    for row in rdr.rows() {
+        let row = row?;
+        if row.succeeded {
+            previous
+                .measurements
+                .update(row.bench, row.values.into_iter());
+        }
+    }
+    // The reader doesn't tell us the run IDs and rounds, so we go through
+    // the rows again
+    let mut rdr = csv::ReaderBuilder::new()
+        .comment(Some(b'#'))
+        .flexible(true)
+        .has_headers(false)
+        .from_reader(contents);
+    for row in rdr.records() {
+        let row = row?;
+        if previous.header.first().is_none_or(|x| x == &row[0]) {
+            previous.header = row.iter().map(|x| x.to_string()).collect();
+            continue;
+        }
+        let get = |name: &str| {
+            let idx = previous.header.iter().position(|x| x == name)?;
+            row.get(idx)?.parse::<usize>().ok()
+        };
+        // With --jobs, rows are written in the order the runs finished, so
+        // the last run ID isn't necessarily the highest.  Without a run_id
+        // column, every row is a new run.
+        previous.n_runs = match get("run_id") {
+            Some(run_id) => previous.n_runs.max(run_id),
+            None => previous.n_runs + 1,
+        };
+        if let Some(round) = get("round") {
+            previous.next_round = previous.next_round.max(round + 1);
+        }
+    }
+    if previous.header.is_empty() {
+        bail!("There's no CSV header");
+    }
+    Ok(Some(previous))
+}
+
+#[derive(Clone, Copy)]
+struct Policies {
+    on_timeout: Policy,
+    on_failure: Policy,
+}
+
+/// The runs which didn't complete so far, including warm-up runs
+#[derive(Default, Debug, PartialEq)]
+struct Failures {
+    runs: usize,
+    timeouts: usize,
+    hooks: usize,
+}
+impl Failures {
+    /// Counts a failed prepare or cleanup command.  These are counted
+    /// separately, but follow the --on-failure policy.
+    fn hook(&mut self, policies: Policies) -> Policy {
+        self.hooks += 1;
+        policies.on_failure
+    }
+
+    /// Counts the run if it didn't complete, and returns the policy which
+    /// applies to it
+    fn run(&mut self, policies: Policies, result: &RunResult) -> Option<Policy> {
+        match result {
+            RunResult::Done(_) => None,
+            RunResult::Failed(_) => {
+                self.runs += 1;
+                Some(policies.on_failure)
+            }
+            RunResult::TimedOut => {
+                self.timeouts += 1;
+                Some(policies.on_timeout)
+            }
+        }
+    }
+}
+
+/// A sampling session, shared between the workers
+struct Session {
+    benches: Vec<Benchmark>,
+    labels: Vec<Bench>,
+    run_timeout: Option<Duration>,
+    policies: Policies,
+    threshold: Option<f64>,
+    /// If `None`, --threshold looks at every metric
+    threshold_metric: Option<Metric>,
+    sig_level: f64,
+    deadline: Option<Instant>,
+    /// Added to the scheduler's round numbers (when appending to earlier
+    /// results)
+    first_round: usize,
+    state: Mutex<State>,
+}
+
+struct State {
+    out: CsvWriter<Box<dyn Write + Send>>,
+    /// The number of runs started so far.  Also used as the run ID.
+    n_started: usize,
+    measurements: Measurements,
+    failures: Failures,
+    /// Set when we've collected enough samples, or a worker failed
+    finished: bool,
+    scheduler: Scheduler,
+}
+
+impl Session {
+    fn work(&self, worker: usize) -> Result<()> {
+        loop {
+            if self.deadline.is_some_and(|t| Instant::now() > t) {
+                return Ok(());
+            }
+            let (idx, round) = {
+                let mut state = self.state.lock().unwrap();
+                if state.finished {
+                    return Ok(());
+                }
+                match state.scheduler.next()? {
+                    Some(x) => x,
+                    // We were replaying a schedule, and it's run out
+                    None => return Ok(()),
+                }
+            };
+            self.run(worker, idx, self.first_round + round)?;
+        }
+    }
+
+    /// Runs the benchmark and records the result
+    fn run(&self, worker: usize, idx: usize, round: usize) -> Result<()> {
+        let run_id = {
+            let mut state = self.state.lock().unwrap();
+            state.n_started += 1;
+            state.n_started
+        };
+        let bench = &self.benches[idx];
+        let (run, hook_failure) = run_with_hooks(bench, self.run_timeout)?;
+
+        let mut state = self.state.lock().unwrap();
+        if let Some(e) = hook_failure {
+            if state.failures.hook(self.policies) == Policy::Abort {
+                bail!("{}: {}", bench, e);
+            }
+            eprintln!("{}: {}", bench, e);
+        }
+        let Some(Run { result, span }) = run else {
+            return Ok(());
+        };
+        match state.failures.run(self.policies, &result) {
+            None => (),
+            Some(Policy::Abort) => bail!("{}: {}", bench, result),
+            Some(Policy::Skip) => {
+                eprintln!("{}: {}; skipping", bench, result);
+                return Ok(());
+            }
+            Some(Policy::Record) => eprintln!("{}: {}; recording", bench, result),
+        }
+
+        let mut info = RunInfo {
+            run_id,
+            round,
+            worker,
+            span,
+            warmup: false,
+        };
+        let samples = match &result {
+            RunResult::Done(samples) => &samples[..],
+            _ => {
+                state
+                    .out
+                    .write_csv(&bench.to_string(), &info, &result, None)?;
+                return Ok(());
+            }
+        };
+        for (i, values) in samples.iter().enumerate() {
+            // Every measurement gets its own run ID
+            if i > 0 {
+                state.n_started += 1;
+                info.run_id = state.n_started;
+            }
+            state
+                .out
+                .write_csv(&bench.to_string(), &info, &result, Some(values))?;
+            let values = values
+                .iter()
+                .map(|(stat, x)| (Metric::from(stat.as_str()), *x));
+            state.measurements.update(self.labels[idx], values);
+            if let Some(threshold) = self.threshold {
+                if !state.finished
+                    && converged(
+                        &state.measurements,
+                        &self.labels,
+                        self.threshold_metric,
+                        self.sig_level,
+                        threshold,
+                    )
+                {
+                    eprintln!("All CIs are narrower than {}%; stopping", threshold);
+                    state.finished = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Checks whether the CI of every (pair, metric) is narrower than `threshold`
+/// (given as a percentage of the base mean).  If `metric` is given, only
+/// that metric is checked.
+fn converged(
+    msmts: &Measurements,
+    labels: &[Bench],
+    metric: Option<Metric>,
+    sig_level: f64,
+    threshold: f64,
+) -> bool {
+    if !labels.iter().all(|&x| msmts.contains(x)) {
+        return false;
+    }
+    labels.windows(2).all(|pair| {
+        let diff = msmts.diff(pair[0], pair[1]);
+        all_metrics().zip(diff).all(|(m, ci)| {
+            if metric.is_some_and(|x| x != m) {
+                return true;
+            }
+            // If a metric is missing from one of the benchmarks, there's
+            // nothing to compare
+            if ci.0.count == 0 || ci.1.count == 0 {
+                return true;
+            }
+            // Counters like major_faults are often constant.  There's no CI
+            // to speak of, and more samples won't give us one.
+            let constant = |x: behrens_fisher::SampleStats| x.count > 1 && x.var == 0.;
+            if constant(ci.0) && constant(ci.1) {
+                return true;
+            }
+            let (l, r) = ci.interval(sig_level);
+            // NaN (eg. too few samples) compares false, so we keep going
+            r - l < threshold
+        })
+    })
+}
+
+/// Which of the optional non-metric columns to include
+#[derive(Clone, Copy)]
+struct Columns {
+    round: bool,
+    run_id: bool,
+    worker: bool,
+    /// start_unix_ns and end_unix_ns
+    timestamps: bool,
+    status: bool,
+    /// Whether the row is from a warm-up run
+    phase: bool,
+}
+
+/// Information about a run, other than its result
+struct RunInfo {
+    /// Runs are numbered from 1, in the order they started
+    run_id: usize,
+    round: usize,
+    worker: usize,
+    span: Span,
+    /// Written to the "phase" column, if there is one
+    warmup: bool,
 }
 
 struct CsvWriter<T> {
     out: T,
+    format: Format,
     stats: Vec<String>,
+    columns: Columns,
 }
 impl<T: Write> CsvWriter<T> {
-    fn new<'a>(mut out: T, stats: impl Iterator<Item = &'a String>) -> Result<CsvWriter<T>> {
-        out.write_all(b"benchmark")?;
-        let stats = stats
-            .map(|x| {
-                write!(out, ",{}", x)?;
-                Ok(x.to_string())
-            })
-            .collect::<Result<Vec<_>>>()?;
-        out.write_all(b"\n")?;
-        Ok(CsvWriter { out, stats })
+    fn new(out: T, format: Format, stats: Vec<String>, columns: Columns) -> Result<CsvWriter<T>> {
+        let mut this = CsvWriter {
+            out,
+            format,
+            stats,
+            columns,
+        };
+        this.write_header()?;
+        Ok(this)
     }
-    fn write_csv(&mut self, bench: &str, values: &BTreeMap<String, f64>) -> Result<()> {
-        write!(self.out, "{}", bench)?;
+    /// Carries on from an existing header, which must have the columns we
+    /// would have written
+    fn append(out: T, format: Format, columns: Columns, header: &[String]) -> Result<CsvWriter<T>> {
+        let mut this = CsvWriter {
+            out,
+            format,
+            stats: vec![],
+            columns,
+        };
+        let expected = this.header();
+        let ok = match format {
+            // The rest should all be metrics
+            Format::Wide => {
+                header.starts_with(&expected)
+                    && header[expected.len()..].iter().all(|x| {
+                        !input::INFO_COLUMNS.contains(&x.as_str())
+                            && !input::STATUS_COLUMNS.contains(&x.as_str())
+                    })
+            }
+            Format::Long => header == expected,
+        };
+        if !ok {
+            let mut expected = expected.join(",");
+            if format == Format::Wide {
+                expected.push_str(",<metrics>");
+            }
+            bail!(
+                "Expected the columns to be {}, but they're {}.  \
+                 Are --format, --run-metadata, and the other options the same as before?",
+                expected,
+                header.join(",")
+            );
+        }
+        this.stats = header[expected.len()..].to_vec();
+        Ok(this)
+    }
+    fn header(&self) -> Vec<String> {
+        let mut header = vec![];
+        if self.format == Format::Long {
+            header.push("run_id");
+        }
+        header.push("benchmark");
+        if self.columns.run_id && self.format == Format::Wide {
+            header.push("run_id");
+        }
+        if self.columns.round {
+            header.push("round");
+        }
+        if self.columns.worker {
+            header.push("worker");
+        }
+        if self.columns.timestamps {
+            header.extend(["start_unix_ns", "end_unix_ns"]);
+        }
+        if self.columns.status {
+            header.extend(["exit_status", "signal"]);
+        }
+        if self.columns.phase {
+            header.push("phase");
+        }
+        let mut header = header.into_iter().map(String::from).collect::<Vec<_>>();
+        match self.format {
+            Format::Wide => header.extend(self.stats.iter().cloned()),
+            Format::Long => header.extend(["metric".into(), "value".into()]),
+        }
+        header
+    }
+    fn write_header(&mut self) -> Result<()> {
+        writeln!(self.out, "{}", self.header().join(","))?;
+        Ok(())
+    }
+    /// Writes a row for one measurement, or for a run which failed (in which
+    /// case `values` is `None`)
+    fn write_csv(
+        &mut self,
+        bench: &str,
+        info: &RunInfo,
+        result: &RunResult,
+        values: Option<&BTreeMap<String, f64>>,
+    ) -> Result<()> {
+        match (self.format, values) {
+            (Format::Wide, _) => self.write_wide(bench, info, result, values),
+            (Format::Long, Some(values)) => {
+                for (stat, x) in values {
+                    self.write_info(bench, info, result)?;
+                    writeln!(self.out, ",{},{}", stat, x)?;
+                }
+                Ok(())
+            }
+            // A failed run gets a single row, with no measurement
+            (Format::Long, _) => {
+                self.write_info(bench, info, result)?;
+                self.out.write_all(b",,\n")?;
+                Ok(())
+            }
+        }
+    }
+    fn write_wide(
+        &mut self,
+        bench: &str,
+        info: &RunInfo,
+        result: &RunResult,
+        values: Option<&BTreeMap<String, f64>>,
+    ) -> Result<()> {
+        // If the benchmark has started reporting new metrics, add columns
+        // for them.  The readers pick up the new header.
+        if let Some(values) = values {
+            let n = self.stats.len();
+            for stat in values.keys() {
+                if !self.stats[..n].contains(stat) {
+                    self.stats.push(stat.clone());
+                }
+            }
+            if self.stats.len() > n {
+                self.write_header()?;
+            }
+        }
+        self.write_info(bench, info, result)?;
         for stat in &self.stats {
-            write!(self.out, ",{}", values.get(stat).unwrap_or(&f64::NAN))?;
+            match values {
+                // Metrics which this run didn't report are left empty
+                Some(values) => match values.get(stat) {
+                    Some(x) => write!(self.out, ",{}", x)?,
+                    None => self.out.write_all(b",")?,
+                },
+                None => write!(self.out, ",{}", f64::NAN)?,
+            }
         }
         self.out.write_all(b"\n")?;
         Ok(())
     }
+    /// Writes the columns which come before the measurements
+    fn write_info(&mut self, bench: &str, info: &RunInfo, result: &RunResult) -> Result<()> {
+        // In the long format, run_id always comes first
+        if self.format == Format::Long {
+            write!(self.out, "{},", info.run_id)?;
+        }
+        write!(self.out, "{}", bench)?;
+        if self.columns.run_id && self.format == Format::Wide {
+            write!(self.out, ",{}", info.run_id)?;
+        }
+        if self.columns.round {
+            write!(self.out, ",{}", info.round)?;
+        }
+        if self.columns.worker {
+            write!(self.out, ",{}", info.worker)?;
+        }
+        if self.columns.timestamps {
+            let unix_ns = |t: SystemTime| t.duration_since(UNIX_EPOCH).map(|x| x.as_nanos());
+            let (start, end) = (unix_ns(info.span.start)?, unix_ns(info.span.end)?);
+            write!(self.out, ",{},{}", start, end)?;
+        }
+        if self.columns.status {
+            match result {
+                RunResult::Done(_) => write!(self.out, ",0,")?,
+                RunResult::Failed(status) => {
+                    self.out.write_all(b",")?;
+                    if let Some(code) = status.code() {
+                        write!(self.out, "{}", code)?;
+                    }
+                    self.out.write_all(b",")?;
+                    #[cfg(unix)]
+                    if let Some(signal) = std::os::unix::process::ExitStatusExt::signal(status) {
+                        write!(self.out, "{}", signal)?;
+                    }
+                }
+                RunResult::TimedOut => write!(self.out, ",timeout,9")?,
+            }
+        }
+        if self.columns.phase {
+            let phase = if info.warmup { "warmup" } else { "measure" };
+            write!(self.out, ",{}", phase)?;
+        }
+        Ok(())
+    }
 }
 
-fn warm_up(benches: &[Benchmark]) -> Result<BTreeSet<String>> {
+/// How much to run each benchmark before we start recording
+struct Warmup {
+    /// At least this many
+    runs: usize,
+    /// Then carry on until the last `window` runs of `metric` have a CV
+    /// (in percent) below this
+    until_stable: Option<f64>,
+    window: usize,
+    metric: String,
+}
+
+/// --warmup-until-stable gives up after this many runs
+const MAX_WARMUP_RUNS: usize = 1000;
+
+/// If a benchmark doesn't complete this many warm-up runs in a row (and
+/// the policy says to carry on), it's probably never going to
+const MAX_WARMUP_FAILURES: usize = 10;
+
+struct WarmedUp {
+    /// The metrics which the benchmarks reported
+    stats: BTreeSet<String>,
+    /// The warm-up runs which completed, with the index of the benchmark
+    runs: Vec<(usize, Run)>,
+    failures: Failures,
+}
+
+/// Runs each benchmark until it's warmed up.  Runs which don't complete
+/// are handled according to `policies`, except that they're never recorded:
+/// they don't count towards the warm-up, so we just try again.
+fn warm_up(
+    benches: &[Benchmark],
+    run_timeout: Option<Duration>,
+    policies: Policies,
+    warmup: &Warmup,
+) -> Result<WarmedUp> {
     let mut stats = BTreeSet::new();
-    for bench in benches {
+    let mut runs = vec![];
+    let mut failures = Failures::default();
+    for (idx, bench) in benches.iter().enumerate() {
         eprintln!("Warming up {}...", bench);
-        let results = run_bench(bench)?;
-        stats.extend(results.keys().cloned());
+        let mut values = vec![];
+        let mut n = 0;
+        let mut n_failed = 0;
+        loop {
+            let (run, hook_failure) = run_with_hooks(bench, run_timeout)?;
+            if let Some(e) = hook_failure {
+                if failures.hook(policies) == Policy::Abort {
+                    bail!("{}: {} during warm-up", bench, e);
+                }
+                eprintln!("{}: {} during warm-up", bench, e);
+            }
+            let run = match run {
+                Some(run) => match failures.run(policies, &run.result) {
+                    None => Some(run),
+                    Some(Policy::Abort) => bail!("{}: {} during warm-up", bench, run.result),
+                    Some(_) => {
+                        eprintln!("{}: {} during warm-up; retrying", bench, run.result);
+                        None
+                    }
+                },
+                // The prepare command failed
+                None => None,
+            };
+            let Some(run) = run else {
+                n_failed += 1;
+                if n_failed == MAX_WARMUP_FAILURES {
+                    bail!(
+                        "{}: {} warm-up runs in a row didn't complete",
+                        bench,
+                        n_failed
+                    );
+                }
+                continue;
+            };
+            let RunResult::Done(samples) = &run.result else {
+                unreachable!()
+            };
+            n += 1;
+            n_failed = 0;
+            stats.extend(samples.iter().flat_map(|x| x.keys().cloned()));
+            values.extend(samples.iter().filter_map(|x| x.get(&warmup.metric)));
+            runs.push((idx, run));
+            if n < warmup.runs {
+                continue;
+            }
+            let Some(threshold) = warmup.until_stable else {
+                break;
+            };
+            if values.is_empty() {
+                bail!(
+                    "{}: --warmup-until-stable needs the \"{}\" metric, but it wasn't reported",
+                    bench,
+                    warmup.metric
+                );
+            }
+            if values.len() >= warmup.window {
+                let cv = coefficient_of_variation(&values[values.len() - warmup.window..]);
+                if cv < threshold {
+                    eprintln!("{}: Stable after {} runs (CV {:.2}%)", bench, n, cv);
+                    break;
+                }
+            }
+            if n >= MAX_WARMUP_RUNS {
+                eprintln!("Warning: {}: Still not stable after {} runs", bench, n);
+                break;
+            }
+        }
     }
     eprintln!();
-    Ok(stats)
+    Ok(WarmedUp {
+        stats,
+        runs,
+        failures,
+    })
+}
+
+/// The standard deviation as a percentage of the mean
+fn coefficient_of_variation(xs: &[f64]) -> f64 {
+    let n = xs.len() as f64;
+    let mean = xs.iter().sum::<f64>() / n;
+    let var = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / (n - 1.);
+    100. * var.sqrt() / mean.abs()
+}
+
+/// time-cmd leaves out any counters it couldn't open.  That's not worth
+/// aborting over (the software counters may still be useful), but we should
+/// at least mention it.
+fn warn_missing_counters(benches: &[Benchmark], stats: &BTreeSet<String>) {
+    let Some(bench) = benches
+        .iter()
+        .find(|x| matches!(x.runner, BenchRunner::Prog(_)))
+    else {
+        return;
+    };
+    let missing = bench
+        .counters
+        .iter()
+        .filter(|&&c| !stats.contains(counter_metric(c)))
+        .map(|c| c.name())
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        eprintln!(
+            "Warning: These performance counters aren't available: {}.  \
+             Hardware counters are often unavailable in VMs and containers; \
+             see also /proc/sys/kernel/perf_event_paranoid.\n",
+            missing.join(", ")
+        );
+    }
 }
 
 struct Benchmark {
     name: Option<String>,
     runner: BenchRunner,
+    /// Run before each run, untimed
+    prepare: Option<String>,
+    /// Run after each run, untimed
+    cleanup: Option<String>,
+    /// Metrics to pull out of the benchmark's output (only for `Prog`)
+    extractors: Vec<Extractor>,
+    /// Performance counters to collect (only for `Prog`)
+    counters: Vec<Counter>,
+    /// Where to create a cgroup for each run (only for `Prog`)
+    cgroup: Option<CgroupRoot>,
+    /// The working directory for the benchmark and its hooks
+    cwd: Option<PathBuf>,
+    /// Extra environment variables for the benchmark and its hooks
+    env: BTreeMap<String, String>,
+    /// Don't inherit our environment; only use `env`
+    clear_env: bool,
+    /// The checkout this benchmark runs in (for --git-base).  It's removed
+    /// when the benchmark is dropped.
+    worktree: Option<Worktree>,
+}
+impl Benchmark {
+    fn new(name: Option<String>, runner: BenchRunner) -> Benchmark {
+        Benchmark {
+            name,
+            runner,
+            prepare: None,
+            cleanup: None,
+            extractors: vec![],
+            counters: vec![],
+            cgroup: None,
+            cwd: None,
+            env: BTreeMap::new(),
+            clear_env: false,
+            worktree: None,
+        }
+    }
+
+    /// A command which will run in the benchmark's environment
+    fn command(&self, program: &str) -> Command {
+        let mut cmd = Command::new(program);
+        if let Some(cwd) = &self.cwd {
+            cmd.current_dir(cwd);
+        }
+        if self.clear_env {
+            cmd.env_clear();
+        }
+        cmd.envs(&self.env);
+        cmd
+    }
+
+    /// Context for errors from running something in the benchmark's
+    /// environment, eg. "the benchmark" or "prepare command"
+    fn context(&self, what: &str) -> String {
+        match &self.cwd {
+            Some(cwd) => format!("{}: Running the {} in {}", self, what, cwd.display()),
+            None => format!("{}: Running the {}", self, what),
+        }
+    }
 }
 enum BenchRunner {
     Prog(String),
     Script(String, Vec<String>),
+    Server(Server),
+}
+impl fmt::Display for BenchRunner {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BenchRunner::Prog(x) => f.write_str(x),
+            BenchRunner::Server(x) => f.write_str(&x.cmd),
+            BenchRunner::Script(x, args) => {
+                f.write_str(x)?;
+                for arg in args {
+                    write!(f, " {}", arg)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 impl fmt::Display for Benchmark {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
@@ -168,50 +1563,796 @@ impl fmt::Display for Benchmark {
         } else {
             match &self.runner {
                 BenchRunner::Prog(x) => f.write_str(x),
+                BenchRunner::Server(x) => f.write_str(&x.cmd),
                 BenchRunner::Script(x, args) => write!(f, "<{} {:?}>", x, args),
             }
         }
     }
 }
 
-fn run_bench(bench: &Benchmark) -> Result<BTreeMap<String, f64>> {
+/// A --prepare or --cleanup command didn't succeed
+struct HookFailure {
+    hook: &'static str,
+    reason: String,
+}
+impl fmt::Display for HookFailure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} command failed: {}", self.hook, self.reason)
+    }
+}
+
+/// Runs a --prepare or --cleanup command
+fn run_hook(
+    bench: &Benchmark,
+    hook: &'static str,
+    cmd: &str,
+    timeout: Option<Duration>,
+) -> Result<Option<HookFailure>> {
+    let mut sh = bench.command("/bin/sh");
+    sh.arg("-c")
+        .arg(cmd)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped());
+    let out = time_cmd_output(sh, timeout)
+        .with_context(|| bench.context(&format!("{} command", hook)))?;
+    let reason = match out.status {
+        Some(status) if status.success() => return Ok(None),
+        Some(status) => match String::from_utf8_lossy(&out.stderr).trim() {
+            "" => status.to_string(),
+            stderr => format!("{} ({})", status, stderr),
+        },
+        None => "Timed out".into(),
+    };
+    Ok(Some(HookFailure { hook, reason }))
+}
+
+/// Runs the benchmark, surrounded by its prepare and cleanup commands.  If
+/// the prepare command fails, the benchmark isn't run.
+fn run_with_hooks(
+    bench: &Benchmark,
+    timeout: Option<Duration>,
+) -> Result<(Option<Run>, Option<HookFailure>)> {
+    if let Some(cmd) = &bench.prepare {
+        if let Some(e) = run_hook(bench, "prepare", cmd, timeout)? {
+            return Ok((None, Some(e)));
+        }
+    }
+    let start = SystemTime::now();
+    let result = run_bench(bench, timeout)?;
+    let span = Span {
+        start,
+        end: SystemTime::now(),
+    };
+    let hook_failure = match &bench.cleanup {
+        Some(cmd) => run_hook(bench, "cleanup", cmd, timeout)?,
+        None => None,
+    };
+    Ok((Some(Run { result, span }), hook_failure))
+}
+
+/// A run of a benchmark which wasn't cut short by its prepare command
+struct Run {
+    result: RunResult,
+    span: Span,
+}
+
+/// When the benchmark itself (not its hooks) started and finished
+#[derive(Clone, Copy)]
+struct Span {
+    start: SystemTime,
+    end: SystemTime,
+}
+
+enum RunResult {
+    /// The benchmark succeeded.  Scripts may report several measurements per
+    /// run; otherwise there's just one.
+    Done(Vec<BTreeMap<String, f64>>),
+    /// The benchmark exited non-zero
+    Failed(ExitStatus),
+    /// The run was killed because it exceeded --run-timeout
+    TimedOut,
+}
+impl fmt::Display for RunResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RunResult::Done(_) => f.write_str("Succeeded"),
+            RunResult::Failed(status) => write!(f, "Benchmark exited non-zero ({})", status),
+            RunResult::TimedOut => f.write_str("Timed out"),
+        }
+    }
+}
+
+/// The units of the metrics which we measure ourselves
+const BUILTIN_UNITS: &[(&str, &str)] = &[
+    ("wall_clock_millis", "ms"),
+    ("user_cpu_millis", "ms"),
+    ("kernel_cpu_millis", "ms"),
+    ("max_rss_kb", "KiB"),
+    ("minor_faults", "count"),
+    ("major_faults", "count"),
+    ("voluntary_ctx_switches", "count"),
+    ("involuntary_ctx_switches", "count"),
+    ("block_inputs", "blocks"),
+    ("block_outputs", "blocks"),
+    ("instructions", "count"),
+    ("cycles", "count"),
+    ("branches", "count"),
+    ("branch_misses", "count"),
+    ("task_clock_millis", "ms"),
+    ("context_switches", "count"),
+    ("page_faults", "count"),
+    ("cgroup_cpu_millis", "ms"),
+    ("cgroup_user_cpu_millis", "ms"),
+    ("cgroup_kernel_cpu_millis", "ms"),
+    ("cgroup_memory_peak_kb", "KiB"),
+    ("cgroup_read_bytes", "bytes"),
+    ("cgroup_write_bytes", "bytes"),
+    ("cgroup_read_ios", "count"),
+    ("cgroup_write_ios", "count"),
+    ("cgroup_pids_peak", "count"),
+];
+
+fn run_bench(bench: &Benchmark, timeout: Option<Duration>) -> Result<RunResult> {
     match &bench.runner {
         BenchRunner::Prog(x) => {
-            let mut cmd = Command::new("/bin/sh");
-            cmd.arg("-c")
-                .arg(x)
-                .stdout(Stdio::null())
-                .stderr(Stdio::null());
-            let (timings, status) = time_cmd(cmd)?;
+            // We only need the output if we're going to look at it
+            let output = || {
+                if bench.extractors.is_empty() {
+                    Stdio::null()
+                } else {
+                    Stdio::piped()
+                }
+            };
+            let mut cmd = bench.command("/bin/sh");
+            cmd.arg("-c").arg(x).stdout(output()).stderr(output());
+            // Dropping this kills anything left in the cgroup
+            let cgroup = bench.cgroup.as_ref().map(|x| x.create()).transpose()?;
+            if let Some(cgroup) = &cgroup {
+                cgroup.add(&mut cmd);
+            }
+            let out = time_cmd_counters(cmd, timeout, &bench.counters)
+                .with_context(|| bench.context("benchmark"))?;
+            let Some(status) = out.status else {
+                return Ok(RunResult::TimedOut);
+            };
             if !status.success() {
-                bail!("{}: Benchmark exited non-zero ({})", bench, x);
+                return Ok(RunResult::Failed(status));
             }
             let mut ret = BTreeMap::default();
+            let timings = out.timings;
             ret.insert(
                 "wall_clock_millis".into(),
                 timings.wall_time.as_secs_f64() * 1000.0,
             );
             ret.insert("user_cpu_millis".into(), timings.user_time * 1000.0);
             ret.insert("kernel_cpu_millis".into(), timings.sys_time * 1000.0);
-            Ok(ret)
+            if let Some(usage) = out.usage {
+                ret.insert("max_rss_kb".into(), usage.max_rss_kb as f64);
+                ret.insert("minor_faults".into(), usage.minor_faults as f64);
+                ret.insert("major_faults".into(), usage.major_faults as f64);
+                ret.insert(
+                    "voluntary_ctx_switches".into(),
+                    usage.voluntary_context_switches as f64,
+                );
+                ret.insert(
+                    "involuntary_ctx_switches".into(),
+                    usage.involuntary_context_switches as f64,
+                );
+                ret.insert("block_inputs".into(), usage.block_inputs as f64);
+                ret.insert("block_outputs".into(), usage.block_outputs as f64);
+            }
+            for &(counter, value) in &out.counters {
+                let value = match counter {
+                    // Reported in nanoseconds
+                    Counter::TaskClock => value as f64 / 1_000_000.0,
+                    _ => value as f64,
+                };
+                ret.insert(counter_metric(counter).into(), value);
+            }
+            if let Some(cgroup) = &cgroup {
+                cgroup.stats(&mut ret)?;
+            }
+            extract::extract_all(&bench.extractors, &out.stdout, &out.stderr, &mut ret);
+            Ok(RunResult::Done(vec![ret]))
         }
         BenchRunner::Script(script, args) => {
-            let out = Command::new(script)
-                .args(args)
-                .stdout(Stdio::piped())
-                .stderr(Stdio::piped())
-                .spawn()?
-                .wait_with_output()?;
-            if !out.status.success() {
-                bail!(
-                    "{}: Benchmark exited non-zero ({} {:?})",
-                    bench,
-                    script,
-                    args
-                );
+            let mut cmd = bench.command(script);
+            cmd.args(args).stdout(Stdio::piped()).stderr(Stdio::piped());
+            let out = time_cmd_output(cmd, timeout).with_context(|| bench.context("benchmark"))?;
+            let Some(status) = out.status else {
+                return Ok(RunResult::TimedOut);
+            };
+            if !status.success() {
+                return Ok(RunResult::Failed(status));
+            }
+            let values = extract::parse_json(&out.stdout)
+                .with_context(|| String::from_utf8_lossy(&out.stderr).into_owned())?;
+            Ok(RunResult::Done(values))
+        }
+        BenchRunner::Server(server) => {
+            let cmd = || {
+                let mut cmd = bench.command("/bin/sh");
+                cmd.arg("-c").arg(&server.cmd);
+                cmd
+            };
+            let reply = server
+                .request(cmd, timeout)
+                .with_context(|| bench.context("server"))?;
+            match reply {
+                Reply::Line(line, elapsed) => {
+                    let mut values = extract::parse_json(line.as_bytes())
+                        .with_context(|| format!("{}: Bad reply", server.cmd))?;
+                    for x in &mut values {
+                        x.entry("round_trip_millis".into())
+                            .or_insert(elapsed.as_secs_f64() * 1000.0);
+                    }
+                    Ok(RunResult::Done(values))
+                }
+                Reply::Died(status) => Ok(RunResult::Failed(status)),
+                Reply::TimedOut => Ok(RunResult::TimedOut),
             }
-            serde_json::from_slice(&out.stdout)
-                .with_context(|| String::from_utf8_lossy(&out.stderr).into_owned())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bpaf::Parser;
+
+    /// Parses `cbdr sample` arguments
+    fn opts(args: &[&str]) -> Options {
+        let args = std::iter::once("sample").chain(args.iter().copied());
+        options()
+            .to_options()
+            .run_inner(&args.collect::<Vec<_>>()[..])
+            .unwrap()
+    }
+
+    fn bench_err(args: &[&str]) -> String {
+        match opts(args).benchmarks(Config::default()) {
+            Ok(_) => panic!("expected {:?} to be rejected", args),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_git_conflicts() {
+        let e = bench_err(&["--git-base=HEAD"]);
+        assert_eq!(e, "--git-base requires --run");
+        for flag in ["--git-head=HEAD", "--build=make", "--run=./bench"] {
+            let e = bench_err(&[flag, "a:true"]);
+            assert_eq!(e, "--git-head, --build, and --run require --git-base");
+        }
+        let e = bench_err(&["--git-base=HEAD", "--git-head=HEAD", "--run=true"]);
+        assert!(
+            e.starts_with("--git-base and --git-head are the same commit"),
+            "{}",
+            e
+        );
+        let e = bench_err(&["--git-base=no-such-rev", "--run=true"]);
+        assert_eq!(e, "Resolving \"no-such-rev\"");
+        let e = bench_err(&["--cwd=nope:/", "a:true"]);
+        assert_eq!(e, "--cwd: There's no benchmark called \"nope\"");
+    }
+
+    #[test]
+    fn test_with_config() {
+        let named = |x: &str| x.parse::<NamedString>().unwrap();
+        let show = |xs: Vec<NamedString>| {
+            xs.into_iter()
+                .map(|NamedString(name, x)| format!("{}:{}", name.unwrap_or_default(), x))
+                .collect::<Vec<_>>()
+        };
+        let targets = || [("a".to_string(), Some("x".to_string())), ("b".into(), None)];
+        let global = || Some("g".to_string());
+        assert_eq!(
+            show(with_config(global(), targets().into_iter(), &[])),
+            [":g", "a:x"]
+        );
+        // The command line's global value replaces the file's
+        let cli = [named("h"), named("b:y")];
+        assert_eq!(
+            show(with_config(global(), targets().into_iter(), &cli)),
+            ["a:x", ":h", "b:y"]
+        );
+    }
+
+    #[test]
+    fn test_env() {
+        let args = [
+            "--env=PATH=/bin:/usr/bin",
+            "--env=A=x=y",
+            "--env=b:A=z",
+            "--clear-env",
+            "a:true",
+            "b:true",
+        ];
+        let benches = opts(&args).benchmarks(Config::default()).unwrap();
+        let env = |idx: usize| {
+            let bench: &Benchmark = &benches[idx];
+            let env = bench.env.iter().map(|(k, v)| format!("{}={}", k, v));
+            (env.collect::<Vec<_>>(), bench.clear_env)
+        };
+        // A value can have "=" (and ":") in it
+        assert_eq!(
+            env(0),
+            (vec!["A=x=y".into(), "PATH=/bin:/usr/bin".into()], true)
+        );
+        // The benchmark's own value wins
+        assert_eq!(
+            env(1),
+            (vec!["A=z".into(), "PATH=/bin:/usr/bin".into()], true)
+        );
+
+        // With --clear-env, the benchmark sees nothing else
+        let out = benches[1].command("/usr/bin/env").output().unwrap();
+        let vars = String::from_utf8(out.stdout).unwrap();
+        let mut vars = vars.lines().collect::<Vec<_>>();
+        vars.sort();
+        assert_eq!(vars, ["A=z", "PATH=/bin:/usr/bin"]);
+
+        let benches = opts(&["--env=A=1", "a:true"])
+            .benchmarks(Config::default())
+            .unwrap();
+        assert!(!benches[0].clear_env);
+
+        let e = bench_err(&["--env=nope:A=1", "a:true"]);
+        assert_eq!(e, "--env: There's no benchmark called \"nope\"");
+        let e = bench_err(&["--env=A", "a:true"]);
+        assert_eq!(e, "--env: Expected KEY=VALUE, but got \"A\"");
+    }
+
+    const PREAMBLE: &str = r#"# seed: 1
+# benchmark: {"label":"foobar","command":"true"}
+# benchmark: {"label":"barqux","command":"false"}
+"#;
+
+    fn columns(run_id: bool, round: bool) -> Columns {
+        Columns {
+            round,
+            run_id,
+            worker: false,
+            timestamps: false,
+            status: false,
+            phase: false,
+        }
+    }
+
+    #[test]
+    fn test_append_wide() {
+        let csv = format!(
+            "{}benchmark,run_id,x\nfoobar,1,1\nbarqux,2,2\nfoobar,3,3\n",
+            PREAMBLE
+        );
+        let previous = parse_previous(csv.as_bytes()).unwrap().unwrap();
+        assert_eq!(previous.labels, ["foobar", "barqux"]);
+        assert_eq!(previous.header, ["benchmark", "run_id", "x"]);
+        assert_eq!(previous.n_runs, 3);
+        assert_eq!(previous.next_round, 0);
+        assert_eq!(previous.measurements.runs(Bench::from("foobar")), 2);
+        assert_eq!(previous.measurements.runs(Bench::from("barqux")), 1);
+
+        let mut out =
+            CsvWriter::append(vec![], Format::Wide, columns(true, false), &previous.header)
+                .unwrap();
+        let info = RunInfo {
+            run_id: previous.n_runs + 1,
+            round: 0,
+            worker: 0,
+            span: Span {
+                start: UNIX_EPOCH,
+                end: UNIX_EPOCH,
+            },
+            warmup: false,
+        };
+        let values = BTreeMap::from([("x".to_string(), 4.)]);
+        let result = RunResult::Done(vec![values.clone()]);
+        out.write_csv("barqux", &info, &result, Some(&values))
+            .unwrap();
+        // No new header, since the columns are the same
+        assert_eq!(String::from_utf8(out.out).unwrap(), "barqux,4,4\n");
+
+        // Different options mean different columns
+        let header = &previous.header;
+        assert!(CsvWriter::append(vec![], Format::Wide, columns(false, false), header).is_err());
+        assert!(CsvWriter::append(vec![], Format::Wide, columns(true, true), header).is_err());
+        assert!(CsvWriter::append(vec![], Format::Long, columns(true, false), header).is_err());
+    }
+
+    #[test]
+    fn test_append_long() {
+        let csv = format!(
+            "{}run_id,benchmark,round,metric,value\n\
+             1,foobar,0,x,1\n1,foobar,0,y,2\n2,barqux,0,x,3\n2,barqux,0,y,4\n",
+            PREAMBLE
+        );
+        let previous = parse_previous(csv.as_bytes()).unwrap().unwrap();
+        assert_eq!(previous.n_runs, 2);
+        assert_eq!(previous.next_round, 1);
+        assert_eq!(previous.measurements.runs(Bench::from("barqux")), 1);
+        let header = &previous.header;
+        let out = CsvWriter::append(vec![], Format::Long, columns(false, true), header).unwrap();
+        assert!(out.stats.is_empty());
+        assert!(CsvWriter::append(vec![], Format::Long, columns(false, false), header).is_err());
+        assert!(CsvWriter::append(vec![], Format::Wide, columns(true, true), header).is_err());
+    }
+
+    #[test]
+    fn test_append_new_header() {
+        let csv = format!(
+            "{}benchmark,round,x\nfoobar,0,1\nbenchmark,round,x,y\nbarqux,0,2,3\nfoobar,1,,4\n",
+            PREAMBLE
+        );
+        let previous = parse_previous(csv.as_bytes()).unwrap().unwrap();
+        // It's the last header which matters
+        assert_eq!(previous.header, ["benchmark", "round", "x", "y"]);
+        assert_eq!(previous.n_runs, 3);
+        assert_eq!(previous.next_round, 2);
+        let header = &previous.header;
+        let out = CsvWriter::append(vec![], Format::Wide, columns(false, true), header).unwrap();
+        assert_eq!(out.stats, ["x", "y"]);
+    }
+
+    #[test]
+    fn test_append_out_of_order() {
+        // With --jobs, runs finish in a different order to the one they
+        // started in
+        let csv = format!(
+            "{}benchmark,run_id,round,worker,x\n\
+             foobar,2,0,1,1\nbarqux,4,1,0,1\nfoobar,1,0,0,1\nbarqux,3,1,1,1\n",
+            PREAMBLE
+        );
+        let previous = parse_previous(csv.as_bytes()).unwrap().unwrap();
+        assert_eq!(previous.n_runs, 4);
+        assert_eq!(previous.next_round, 2);
+    }
+
+    #[test]
+    fn test_append_empty() {
+        // There's no header yet if we were interrupted during warm-up
+        assert!(parse_previous(b"").unwrap().is_none());
+        assert!(parse_previous(PREAMBLE.as_bytes()).unwrap().is_none());
+
+        let path = std::env::temp_dir().join(format!("cbdr-test-append-{}", std::process::id()));
+        File::create(&path).unwrap();
+        let path_arg = path.to_str().unwrap();
+        let sample = || sample(opts(&["--append", path_arg, "-t", "100ms", "a:true"]));
+        sample().unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        assert!(written.starts_with("# cbdr-version: "), "{}", written);
+        let previous = parse_previous(written.as_bytes()).unwrap().unwrap();
+        assert_eq!(previous.labels, ["a"]);
+        assert_eq!(previous.header[0], "benchmark");
+        // Now that there's a header, we carry on from it
+        sample().unwrap();
+        let written = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(written.contains("\n# Resumed with seed "), "{}", written);
+    }
+
+    #[test]
+    fn test_converged() {
+        let labels = [Bench::from("converged_a"), Bench::from("converged_b")];
+        let [time, switches] = [Metric::from("time"), Metric::from("switches")];
+        let mut msmts = Measurements::default();
+        for i in 0..100 {
+            // Constant within each benchmark, but different between them
+            msmts.update(
+                labels[0],
+                [(time, 100. + (i % 2) as f64), (switches, 1.)].into_iter(),
+            );
+            msmts.update(
+                labels[1],
+                [(time, 110. + (i % 2) as f64), (switches, 5.)].into_iter(),
+            );
+        }
+        assert!(converged(&msmts, &labels, None, 0.95, 1.));
+        assert!(converged(&msmts, &labels, Some(time), 0.95, 1.));
+        assert!(!converged(&msmts, &labels, Some(time), 0.95, 0.01));
+
+        // A noisy metric near zero holds things up, unless it's ignored
+        let noise = Metric::from("noise");
+        for i in 0..100 {
+            msmts.update(labels[0], [(noise, (i % 3) as f64)].into_iter());
+            msmts.update(labels[1], [(noise, (i % 2) as f64)].into_iter());
+        }
+        assert!(!converged(&msmts, &labels, None, 0.95, 1.));
+        assert!(converged(&msmts, &labels, Some(time), 0.95, 1.));
+    }
+
+    /// Lets a test read back what a `Session` wrote
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::sync::Arc<Mutex<Vec<u8>>>);
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+    impl SharedBuf {
+        /// The rows written so far, without the headers
+        fn rows(&self) -> Vec<String> {
+            let out = String::from_utf8(self.0.lock().unwrap().clone()).unwrap();
+            out.lines()
+                .filter(|x| !x.starts_with("benchmark,"))
+                .map(String::from)
+                .collect()
+        }
+    }
+
+    fn policies(on_failure: Policy, on_timeout: Policy) -> Policies {
+        Policies {
+            on_timeout,
+            on_failure,
+        }
+    }
+
+    /// "ok" succeeds, "fail" exits with 3, and "hang" times out
+    const POLICY_BENCHES: &[&str] = &["ok:true", "fail:exit 3", "hang:sleep 10"];
+
+    /// A session with a --run-timeout of 100ms
+    fn test_session(args: &[&str], policies: Policies) -> (Session, SharedBuf) {
+        let benches = opts(args).benchmarks(Config::default()).unwrap();
+        let buf = SharedBuf::default();
+        let columns = Columns {
+            status: true,
+            ..columns(false, false)
+        };
+        let out = CsvWriter::new(
+            Box::new(buf.clone()) as Box<_>,
+            Format::Wide,
+            vec![],
+            columns,
+        );
+        let n_benches = benches.len();
+        let session = Session {
+            labels: benches
+                .iter()
+                .map(|x| Bench::from(x.to_string().as_str()))
+                .collect(),
+            benches,
+            run_timeout: Some(Duration::from_millis(100)),
+            policies,
+            threshold: None,
+            threshold_metric: None,
+            sig_level: 0.95,
+            deadline: None,
+            first_round: 0,
+            state: Mutex::new(State {
+                out: out.unwrap(),
+                n_started: 0,
+                measurements: Measurements::default(),
+                failures: Failures::default(),
+                finished: false,
+                scheduler: Scheduler::new(Schedule::Random, n_benches, 0),
+            }),
+        };
+        (session, buf)
+    }
+
+    #[test]
+    fn test_policies() {
+        let (session, buf) = test_session(POLICY_BENCHES, policies(Policy::Record, Policy::Skip));
+        for idx in 0..3 {
+            session.run(0, idx, 0).unwrap();
+        }
+        let rows = buf.rows();
+        assert_eq!(rows.len(), 2, "{:?}", rows);
+        assert!(rows[0].starts_with("ok,0,,"), "{}", rows[0]);
+        assert!(rows[1].starts_with("fail,3,,NaN"), "{}", rows[1]);
+        let expected = Failures {
+            runs: 1,
+            timeouts: 1,
+            hooks: 0,
+        };
+        assert_eq!(session.state.lock().unwrap().failures, expected);
+
+        // The other way round
+        let (session, buf) = test_session(POLICY_BENCHES, policies(Policy::Skip, Policy::Record));
+        for idx in 0..3 {
+            session.run(0, idx, 0).unwrap();
+        }
+        let rows = buf.rows();
+        assert_eq!(rows.len(), 2, "{:?}", rows);
+        assert!(rows[1].starts_with("hang,timeout,9,NaN"), "{}", rows[1]);
+        assert_eq!(session.state.lock().unwrap().failures, expected);
+
+        // Aborting on failure doesn't mean aborting on timeout
+        let (session, buf) = test_session(POLICY_BENCHES, policies(Policy::Abort, Policy::Skip));
+        session.run(0, 2, 0).unwrap();
+        let e = session.run(0, 1, 0).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "fail: Benchmark exited non-zero (exit status: 3)"
+        );
+        assert!(buf.rows().is_empty());
+    }
+
+    #[test]
+    fn test_warm_up_policies() {
+        let warmup = Warmup {
+            runs: 3,
+            until_stable: None,
+            window: 0,
+            metric: "wall_clock_millis".into(),
+        };
+        // Fails the first two times
+        let path = std::env::temp_dir().join(format!("cbdr-test-warmup-{}", std::process::id()));
+        let flaky = format!(
+            "flaky:n=$(cat {0} 2>/dev/null || echo 0); echo $((n + 1)) > {0}; [ $n -ge 2 ]",
+            path.display()
+        );
+        let benches = opts(&[&flaky]).benchmarks(Config::default()).unwrap();
+        let flaky = |policies| warm_up(&benches, None, policies, &warmup);
+        let e = flaky(policies(Policy::Abort, Policy::Skip)).err().unwrap();
+        assert_eq!(
+            e.to_string(),
+            "flaky: Benchmark exited non-zero (exit status: 1) during warm-up"
+        );
+        std::fs::remove_file(&path).unwrap();
+        // Failed runs are retried, and never recorded
+        let warmed_up = flaky(policies(Policy::Record, Policy::Abort)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(warmed_up.runs.len(), 3);
+        let expected = Failures {
+            runs: 2,
+            timeouts: 0,
+            hooks: 0,
+        };
+        assert_eq!(warmed_up.failures, expected);
+
+        // A benchmark which never completes is given up on, whichever
+        // policy applies
+        let (session, _) = test_session(POLICY_BENCHES, policies(Policy::Abort, Policy::Skip));
+        let stuck = |idx: usize, policies| {
+            let benches = &session.benches[idx..idx + 1];
+            warm_up(benches, session.run_timeout, policies, &warmup)
+                .err()
+                .unwrap()
+                .to_string()
+        };
+        let e = stuck(2, policies(Policy::Abort, Policy::Skip));
+        assert_eq!(e, "hang: 10 warm-up runs in a row didn't complete");
+        let e = stuck(2, policies(Policy::Skip, Policy::Abort));
+        assert_eq!(e, "hang: Timed out during warm-up");
+        let e = stuck(1, policies(Policy::Skip, Policy::Abort));
+        assert_eq!(e, "fail: 10 warm-up runs in a row didn't complete");
+    }
+
+    #[test]
+    fn test_hooks() {
+        let skip = policies(Policy::Skip, Policy::Skip);
+        // A failing prepare command means the benchmark isn't run
+        let (session, buf) = test_session(&["--prepare=exit 1", "a:true"], skip);
+        let (run, e) = run_with_hooks(&session.benches[0], None).unwrap();
+        assert!(run.is_none());
+        assert_eq!(
+            e.unwrap().to_string(),
+            "prepare command failed: exit status: 1"
+        );
+        session.run(0, 0, 0).unwrap();
+        assert!(buf.rows().is_empty());
+        let failures = &session.state.lock().unwrap().failures;
+        assert_eq!((failures.runs, failures.hooks), (0, 1));
+        let abort = policies(Policy::Abort, Policy::Skip);
+        let (session, _) = test_session(&["--prepare=exit 1", "a:true"], abort);
+        let e = session.run(0, 0, 0).unwrap_err().to_string();
+        assert_eq!(e, "a: prepare command failed: exit status: 1");
+
+        // A failing cleanup command is counted, but the run is kept
+        let (session, buf) = test_session(&["--cleanup=echo oops >&2; exit 1", "a:true"], skip);
+        let (run, e) = run_with_hooks(&session.benches[0], None).unwrap();
+        assert!(run.is_some());
+        let e = e.unwrap().to_string();
+        assert_eq!(e, "cleanup command failed: exit status: 1 (oops)");
+        session.run(0, 0, 0).unwrap();
+        assert_eq!(buf.rows().len(), 1);
+        let failures = &session.state.lock().unwrap().failures;
+        assert_eq!((failures.runs, failures.hooks), (0, 1));
+
+        // The hooks aren't timed
+        let args = ["--prepare=sleep 0.3", "--cleanup=sleep 0.3", "a:true"];
+        let (session, _) = test_session(&args, skip);
+        let (run, e) = run_with_hooks(&session.benches[0], None).unwrap();
+        assert!(e.is_none());
+        let Run { result, span } = run.unwrap();
+        let elapsed = span.end.duration_since(span.start).unwrap();
+        assert!(elapsed < Duration::from_millis(300), "{:?}", elapsed);
+        let RunResult::Done(samples) = result else {
+            panic!("{}", result)
+        };
+        assert!(samples[0]["wall_clock_millis"] < 300.);
+    }
+
+    #[test]
+    fn test_run_metadata() {
+        let columns = Columns {
+            round: true,
+            run_id: true,
+            worker: true,
+            timestamps: true,
+            status: true,
+            phase: true,
+        };
+        let info = |run_id, warmup| RunInfo {
+            run_id,
+            round: 3,
+            worker: 2,
+            span: Span {
+                start: UNIX_EPOCH + Duration::from_nanos(1000),
+                end: UNIX_EPOCH + Duration::from_nanos(2500),
+            },
+            warmup,
+        };
+        let values = BTreeMap::from([("meta_x".to_string(), 4.), ("meta_y".to_string(), 5.)]);
+        let done = RunResult::Done(vec![values.clone()]);
+        for format in [Format::Wide, Format::Long] {
+            let stats = vec!["meta_x".into(), "meta_y".into()];
+            let mut out = CsvWriter::new(vec![], format, stats, columns).unwrap();
+            out.write_csv("foobar", &info(1, true), &done, Some(&values))
+                .unwrap();
+            out.write_csv("foobar", &info(2, false), &done, Some(&values))
+                .unwrap();
+            out.write_csv("barqux", &info(3, false), &RunResult::TimedOut, None)
+                .unwrap();
+            let csv = String::from_utf8(out.out).unwrap();
+
+            // Each value is under the right header
+            let mut rdr = csv::Reader::from_reader(csv.as_bytes());
+            let header = rdr.headers().unwrap().clone();
+            let rows = rdr
+                .records()
+                .map(|row| {
+                    let row = row.unwrap();
+                    let cells = header.iter().zip(row.iter());
+                    cells
+                        .map(|(k, v)| (k.to_string(), v.to_string()))
+                        .collect::<BTreeMap<_, _>>()
+                })
+                .collect::<Vec<_>>();
+            let row = rows.iter().find(|x| x["run_id"] == "2").unwrap();
+            assert_eq!(row["benchmark"], "foobar", "{}", csv);
+            assert_eq!(row["round"], "3");
+            assert_eq!(row["worker"], "2");
+            assert_eq!(row["start_unix_ns"], "1000");
+            assert_eq!(row["end_unix_ns"], "2500");
+            assert_eq!(row["exit_status"], "0");
+            assert_eq!(row["signal"], "");
+            assert_eq!(row["phase"], "measure");
+            let row = rows.iter().find(|x| x["run_id"] == "3").unwrap();
+            assert_eq!(row["exit_status"], "timeout");
+            assert_eq!(row["phase"], "measure");
+            match format {
+                Format::Wide => {
+                    assert_eq!(row["meta_x"], "NaN");
+                    assert_eq!(rows[1]["meta_x"], "4");
+                    assert_eq!(rows[1]["meta_y"], "5");
+                }
+                Format::Long => {
+                    assert_eq!(row["metric"], "");
+                    assert_eq!(rows[2]["metric"], "meta_x");
+                    assert_eq!(rows[2]["value"], "4");
+                }
+            }
+
+            // analyze and diff only see the metrics, and skip the warm-up
+            // and timed-out runs
+            let rows = input::Reader::new(csv.as_bytes()).unwrap().rows();
+            let rows = rows
+                .map(|row| {
+                    let row = row.unwrap();
+                    let values = row.values.iter().map(|(m, x)| (m.to_string(), *x));
+                    (row.bench, row.succeeded, values.collect::<Vec<_>>())
+                })
+                .collect::<Vec<_>>();
+            let expected = vec![("meta_x".to_string(), 4.), ("meta_y".to_string(), 5.)];
+            assert_eq!(rows.len(), 2, "{:?}", format);
+            assert_eq!(rows[0], (Bench::from("foobar"), true, expected));
+            assert_eq!((rows[1].0, rows[1].1), (Bench::from("barqux"), false));
         }
     }
 }
@@ -0,0 +1,209 @@
+use anyhow::{bail, Context, Result};
+use rand::prelude::*;
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+/// How `cbdr sample` chooses which benchmark to run next
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Schedule {
+    /// Pick a benchmark uniformly at random each time
+    Random,
+    /// Run every benchmark once per round, in a random order
+    ShuffledRounds,
+    /// Run every benchmark in order, then again in reverse (eg. ABBA).  This
+    /// cancels out any linear drift.
+    Abba,
+    /// Run every benchmark once per round, in order
+    RoundRobin,
+}
+
+impl FromStr for Schedule {
+    type Err = anyhow::Error;
+    fn from_str(x: &str) -> Result<Schedule> {
+        match x {
+            "random" => Ok(Schedule::Random),
+            "shuffled-rounds" => Ok(Schedule::ShuffledRounds),
+            "abba" => Ok(Schedule::Abba),
+            "round-robin" => Ok(Schedule::RoundRobin),
+            _ => bail!("Expected \"random\", \"shuffled-rounds\", \"abba\", or \"round-robin\""),
+        }
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Schedule::Random => f.write_str("random"),
+            Schedule::ShuffledRounds => f.write_str("shuffled-rounds"),
+            Schedule::Abba => f.write_str("abba"),
+            Schedule::RoundRobin => f.write_str("round-robin"),
+        }
+    }
+}
+
+/// Produces the sequence of benchmarks to run, one round at a time
+pub struct Scheduler {
+    /// `None` if we're replaying a recorded schedule
+    schedule: Option<Schedule>,
+    n_benches: usize,
+    rng: StdRng,
+    round: usize,
+    /// The benchmarks remaining in the current round, and their round numbers
+    queue: VecDeque<(usize, usize)>,
+    recording: Option<LineWriter<File>>,
+}
+
+impl Scheduler {
+    /// The sequence of benchmarks is fully determined by `seed`
+    pub fn new(schedule: Schedule, n_benches: usize, seed: u64) -> Scheduler {
+        Scheduler {
+            schedule: Some(schedule),
+            n_benches,
+            rng: StdRng::seed_from_u64(seed),
+            round: 0,
+            queue: VecDeque::new(),
+            recording: None,
+        }
+    }
+
+    /// Replays a schedule which was previously recorded with `record_to()`.
+    /// Once it runs out, `next()` will return `None`.
+    pub fn replay(path: &Path, n_benches: usize) -> Result<Scheduler> {
+        let mut queue = VecDeque::new();
+        for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let line = line?;
+            let parse = || {
+                let (round, idx) = line.split_once(' ')?;
+                Some((idx.parse::<usize>().ok()?, round.parse::<usize>().ok()?))
+            };
+            let (idx, round) =
+                parse().with_context(|| format!("{}:{}: Bad line", path.display(), i + 1))?;
+            if idx >= n_benches {
+                bail!(
+                    "{}:{}: There are only {} benchmarks",
+                    path.display(),
+                    i + 1,
+                    n_benches
+                );
+            }
+            queue.push_back((idx, round));
+        }
+        Ok(Scheduler {
+            schedule: None,
+            n_benches,
+            rng: StdRng::seed_from_u64(0),
+            round: 0,
+            queue,
+            recording: None,
+        })
+    }
+
+    /// Writes the sequence of benchmarks to the given file as they're chosen.
+    /// Each line contains the round number and the benchmark's index.
+    pub fn record_to(&mut self, path: &Path) -> Result<()> {
+        self.recording = Some(LineWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    /// Returns the index of the next benchmark to run, and the round it
+    /// belongs to.  Rounds are numbered from 1.
+    ///
+    /// For the "random" schedule, a round is just `n_benches` picks.
+    pub fn next(&mut self) -> Result<Option<(usize, usize)>> {
+        if self.queue.is_empty() {
+            self.next_round();
+        }
+        let Some((idx, round)) = self.queue.pop_front() else {
+            return Ok(None);
+        };
+        if let Some(out) = &mut self.recording {
+            writeln!(out, "{} {}", round, idx)?;
+        }
+        Ok(Some((idx, round)))
+    }
+
+    fn next_round(&mut self) {
+        let Some(schedule) = self.schedule else {
+            return;
+        };
+        self.round += 1;
+        let n = self.n_benches;
+        let round = self.round;
+        let idxs = match schedule {
+            Schedule::Random => (0..n).map(|_| self.rng.gen_range(0..n)).collect(),
+            Schedule::ShuffledRounds => {
+                let mut xs = (0..n).collect::<Vec<_>>();
+                xs.shuffle(&mut self.rng);
+                xs
+            }
+            Schedule::Abba => (0..n).chain((0..n).rev()).collect(),
+            Schedule::RoundRobin => (0..n).collect::<Vec<_>>(),
+        };
+        self.queue.extend(idxs.into_iter().map(|idx| (idx, round)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn take(schedule: Schedule, n_benches: usize, n: usize) -> Vec<(usize, usize)> {
+        let mut scheduler = Scheduler::new(schedule, n_benches, 0);
+        (0..n).map(|_| scheduler.next().unwrap().unwrap()).collect()
+    }
+
+    #[test]
+    fn test_round_robin() {
+        assert_eq!(
+            take(Schedule::RoundRobin, 2, 5),
+            vec![(0, 1), (1, 1), (0, 2), (1, 2), (0, 3)]
+        );
+    }
+
+    #[test]
+    fn test_abba() {
+        let xs = take(Schedule::Abba, 3, 8);
+        let idxs = xs.iter().map(|x| x.0).collect::<Vec<_>>();
+        let rounds = xs.iter().map(|x| x.1).collect::<Vec<_>>();
+        assert_eq!(idxs, vec![0, 1, 2, 2, 1, 0, 0, 1]);
+        assert_eq!(rounds, vec![1, 1, 1, 1, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn test_shuffled_rounds() {
+        let xs = take(Schedule::ShuffledRounds, 4, 40);
+        for (i, round) in xs.chunks(4).enumerate() {
+            let mut idxs = round.iter().map(|x| x.0).collect::<Vec<_>>();
+            idxs.sort();
+            assert_eq!(idxs, vec![0, 1, 2, 3]);
+            assert!(round.iter().all(|x| x.1 == i + 1));
+        }
+    }
+
+    #[test]
+    fn test_seed() {
+        let mut x = Scheduler::new(Schedule::Random, 10, 42);
+        let mut y = Scheduler::new(Schedule::Random, 10, 42);
+        for _ in 0..100 {
+            assert_eq!(x.next().unwrap(), y.next().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_record_replay() {
+        let path = std::env::temp_dir().join(format!("cbdr-test-{}", std::process::id()));
+        let mut x = Scheduler::new(Schedule::ShuffledRounds, 3, 1);
+        x.record_to(&path).unwrap();
+        let recorded = (0..7).map(|_| x.next().unwrap()).collect::<Vec<_>>();
+        drop(x);
+        let mut y = Scheduler::replay(&path, 3).unwrap();
+        let replayed = std::iter::from_fn(|| y.next().unwrap()).collect::<Vec<_>>();
+        assert!(Scheduler::replay(&path, 2).is_err());
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(replayed, recorded.into_iter().flatten().collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,238 @@
+use anyhow::{Context, Result};
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A long-lived benchmark process.  Each time we want a measurement, we
+/// write "run" to its stdin, and it replies with a line of JSON on stdout.
+///
+/// The process is started on the first request.  If it dies, it's restarted
+/// on the next one.  Whenever it's started, we send it an extra request and
+/// ignore the reply, so that its start-up time isn't measured.
+pub struct Server {
+    pub cmd: String,
+    process: Mutex<Option<Process>>,
+}
+
+struct Process {
+    child: Child,
+    stdin: ChildStdin,
+    /// Lines from the child's stdout.  These are read on a separate thread
+    /// so that we can time out.
+    lines: Receiver<std::io::Result<String>>,
+}
+
+pub enum Reply {
+    /// The server replied with this line, after this long
+    Line(String, Duration),
+    /// The server exited instead of replying
+    Died(ExitStatus),
+    /// The server didn't reply in time, so it was killed
+    TimedOut,
+}
+
+impl Server {
+    pub fn new(cmd: String) -> Server {
+        Server {
+            cmd,
+            process: Mutex::new(None),
+        }
+    }
+
+    /// Sends a request and waits for the reply.  `cmd` is used to start the
+    /// server if it's not running.  Concurrent requests are handled one at
+    /// a time.
+    pub fn request(
+        &self,
+        cmd: impl FnOnce() -> Command,
+        timeout: Option<Duration>,
+    ) -> Result<Reply> {
+        let mut process = self.process.lock().unwrap();
+        if let Some(p) = process.as_mut() {
+            if let Some(status) = p.child.try_wait()? {
+                eprintln!("{}: Server exited ({}); restarting", self.cmd, status);
+                *process = None;
+            }
+        }
+        let mut started = false;
+        let p = match process.as_mut() {
+            Some(p) => p,
+            None => {
+                started = true;
+                process.insert(Process::spawn(cmd()).context("Starting server")?)
+            }
+        };
+        if started {
+            // The first reply includes the time the server took to start up,
+            // so we throw it away
+            let reply = p.send(timeout);
+            match finish(&mut process, reply)? {
+                Reply::Line(..) => (),
+                x => return Ok(x),
+            }
+        }
+        let reply = process.as_mut().unwrap().send(timeout);
+        finish(&mut process, reply)
+    }
+}
+
+/// Turns the result of `Process::send()` into a `Reply`, getting rid of the
+/// process if it's no good any more
+fn finish(
+    process: &mut Option<Process>,
+    (reply, elapsed): (Result<std::io::Result<String>, RecvTimeoutError>, Duration),
+) -> Result<Reply> {
+    match reply {
+        Ok(line) => Ok(Reply::Line(line?, elapsed)),
+        Err(RecvTimeoutError::Timeout) => {
+            *process = None;
+            Ok(Reply::TimedOut)
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            let status = process.take().unwrap().kill()?;
+            Ok(Reply::Died(status))
+        }
+    }
+}
+
+impl Process {
+    fn spawn(mut cmd: Command) -> Result<Process> {
+        // Its own process group, so we can kill anything it spawns too
+        cmd.stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .process_group(0);
+        let mut child = cmd.spawn()?;
+        lock_groups().push(child.id() as libc::pid_t);
+        let stdin = child.stdin.take().unwrap();
+        let stdout = BufReader::new(child.stdout.take().unwrap());
+        let (tx, lines) = mpsc::channel();
+        std::thread::spawn(move || {
+            for line in stdout.lines() {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(Process {
+            child,
+            stdin,
+            lines,
+        })
+    }
+
+    /// Sends "run" and waits for a line in reply.  Returns how long that
+    /// took.
+    fn send(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> (Result<std::io::Result<String>, RecvTimeoutError>, Duration) {
+        let start = Instant::now();
+        let sent = self
+            .stdin
+            .write_all(b"run\n")
+            .and_then(|()| self.stdin.flush());
+        let reply = match sent {
+            Ok(()) => match timeout {
+                Some(t) => self.lines.recv_timeout(t),
+                None => self
+                    .lines
+                    .recv()
+                    .map_err(|_| RecvTimeoutError::Disconnected),
+            },
+            // It probably exited.  The caller will find out.
+            Err(_) => Err(RecvTimeoutError::Disconnected),
+        };
+        (reply, start.elapsed())
+    }
+
+    /// Kills the process (and anything it spawned), if it's still running
+    fn kill(&mut self) -> std::io::Result<ExitStatus> {
+        let pgid = self.child.id() as libc::pid_t;
+        lock_groups().retain(|&x| x != pgid);
+        if self.child.try_wait()?.is_none() {
+            unsafe { libc::kill(-pgid, libc::SIGKILL) };
+        }
+        self.child.wait()
+    }
+}
+
+/// The process groups of the running servers.  They're not in our process
+/// group, so they don't get the terminal's SIGINT.
+static GROUPS: Mutex<Vec<libc::pid_t>> = Mutex::new(Vec::new());
+
+fn lock_groups() -> std::sync::MutexGuard<'static, Vec<libc::pid_t>> {
+    GROUPS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Kills all the servers (and anything they spawned)
+pub fn kill_all() {
+    for &pgid in lock_groups().iter() {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+}
+
+impl Drop for Process {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sh(script: &'static str) -> impl FnOnce() -> Command {
+        move || {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c").arg(script);
+            cmd
+        }
+    }
+
+    #[test]
+    fn test_reply() {
+        let script = r#"while read x; do echo "{\"a\":1}"; done"#;
+        let server = Server::new(script.into());
+        for _ in 0..3 {
+            match server.request(sh(script), None).unwrap() {
+                Reply::Line(line, _) => assert_eq!(line, r#"{"a":1}"#),
+                _ => panic!("expected a reply"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_timeout() {
+        let script = "while read x; do sleep 10; done";
+        let server = Server::new(script.into());
+        let timeout = Some(Duration::from_millis(100));
+        let reply = server.request(sh(script), timeout).unwrap();
+        assert!(matches!(reply, Reply::TimedOut));
+        assert!(server.process.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_restart() {
+        // Replies to the discarded request and one more, then exits
+        let script = "read x; echo start; read x; echo $$";
+        let server = Server::new(script.into());
+        let pid = |reply| match reply {
+            Reply::Line(line, _) => line,
+            _ => panic!("expected a reply"),
+        };
+        let first = pid(server.request(sh(script), None).unwrap());
+        std::thread::sleep(Duration::from_millis(100));
+        let second = pid(server.request(sh(script), None).unwrap());
+        assert_ne!(first, second);
+
+        let script = "read x; echo start; read x; exit 3";
+        let server = Server::new(script.into());
+        match server.request(sh(script), None).unwrap() {
+            Reply::Died(status) => assert_eq!(status.code(), Some(3)),
+            _ => panic!("expected the server to die"),
+        }
+    }
+}
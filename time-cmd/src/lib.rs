@@ -20,75 +20,565 @@ match time_cmd(Command::new("ls")) {
 }
 ```
 
+If the process might hang, you can give it a deadline:
+
+```
+use std::{process::Command, time::Duration};
+use time_cmd::*;
+
+let mut cmd = Command::new("sleep");
+cmd.arg("10");
+let out = time_cmd_output(cmd, Some(Duration::from_millis(100))).unwrap();
+assert!(out.status.is_none()); // It was killed
+```
+
+On unix, a command with a deadline is put in its own process group.  This
+means it won't get the terminal's SIGINT when the user hits ctrl-C, so if
+your program handles SIGINT, call `kill_running` to take such commands down
+with it.
+
+## Concurrency
+
+On unix, the child is reaped with `wait4(2)`, so the reported CPU time and
+resource usage belong to that child (and any descendants it waited for)
+alone.  It's fine to time several commands at once from different threads,
+or to use this crate in a program which spawns other processes.  The only
+requirement is that nothing else in the process reaps children it doesn't
+own (eg. by calling `waitpid(-1, ...)`).
+
+## Performance counters
+
+On Linux, `time_cmd_counters` can also collect the child's performance
+counters (instructions retired, cycles, etc.) using `perf_event_open(2)`.
+
+```
+use std::process::Command;
+use time_cmd::*;
+
+let out = time_cmd_counters(Command::new("ls"), None, &Counter::ALL).unwrap();
+for (counter, value) in out.counters {
+    println!("{}: {}", counter, value);
+}
+```
+
+The counters are opened on the calling thread just before the child is
+spawned, and inherited by the child (and its descendants) when it execs.
+Like the rusage, they're per-thread, so timing several commands at once
+from different threads is fine.
+
+Counters which can't be opened are left out of the results.  In particular,
+VMs and containers often have no access to the hardware counters
+(instructions, cycles, branches, branch misses); in this case you'll only
+get the software ones (task clock, context switches, page faults).  If
+`perf_event_paranoid` forbids counting kernel events, only userspace events
+are counted.
+
 */
 
-use std::io::Result;
+#[cfg(target_os = "linux")]
+mod perf;
+
+use std::fmt;
+use std::io::{Read, Result};
 use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::thread::JoinHandle;
 use std::time::{Duration, Instant};
 
 #[derive(Clone, Copy, PartialEq, Debug)]
 pub struct Timings {
     pub wall_time: Duration,
+    /// CPU time spent in user mode, in seconds
     pub user_time: f64,
+    /// CPU time spent in the kernel, in seconds
     pub sys_time: f64,
 }
 
+/// Resources used by the child, as reported by `getrusage(2)`
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ResourceUsage {
+    /// Peak resident set size, in kilobytes
+    pub max_rss_kb: u64,
+    /// Page faults serviced without any I/O
+    pub minor_faults: u64,
+    /// Page faults which required I/O
+    pub major_faults: u64,
+    /// Times the process blocked (eg. waiting for I/O) and gave up the CPU
+    pub voluntary_context_switches: u64,
+    /// Times the process was preempted by the scheduler
+    pub involuntary_context_switches: u64,
+    /// Blocks read from the filesystem
+    pub block_inputs: u64,
+    /// Blocks written to the filesystem
+    pub block_outputs: u64,
+}
+
+/// A performance counter which can be collected with `time_cmd_counters`
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum Counter {
+    /// Instructions retired (hardware)
+    Instructions,
+    /// CPU cycles (hardware)
+    Cycles,
+    /// Branch instructions retired (hardware)
+    Branches,
+    /// Mispredicted branches (hardware)
+    BranchMisses,
+    /// Time spent on the CPU, in nanoseconds (software)
+    TaskClock,
+    /// Context switches (software)
+    ContextSwitches,
+    /// Page faults (software)
+    PageFaults,
+}
+
+impl Counter {
+    pub const ALL: [Counter; 7] = [
+        Counter::Instructions,
+        Counter::Cycles,
+        Counter::Branches,
+        Counter::BranchMisses,
+        Counter::TaskClock,
+        Counter::ContextSwitches,
+        Counter::PageFaults,
+    ];
+
+    /// The name `perf stat` uses for this counter
+    pub fn name(self) -> &'static str {
+        match self {
+            Counter::Instructions => "instructions",
+            Counter::Cycles => "cycles",
+            Counter::Branches => "branches",
+            Counter::BranchMisses => "branch-misses",
+            Counter::TaskClock => "task-clock",
+            Counter::ContextSwitches => "context-switches",
+            Counter::PageFaults => "page-faults",
+        }
+    }
+}
+
+impl fmt::Display for Counter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Returned when parsing a `Counter` fails
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct UnknownCounter(pub String);
+
+impl fmt::Display for UnknownCounter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let names: Vec<_> = Counter::ALL.iter().map(|c| c.name()).collect();
+        write!(
+            f,
+            "Unknown counter \"{}\" (expected one of: {})",
+            self.0,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnknownCounter {}
+
+impl FromStr for Counter {
+    type Err = UnknownCounter;
+    /// Accepts the `perf stat` names, with either dashes or underscores
+    fn from_str(s: &str) -> std::result::Result<Counter, UnknownCounter> {
+        let name = s.replace('_', "-");
+        Counter::ALL
+            .iter()
+            .copied()
+            .find(|c| c.name() == name)
+            .ok_or_else(|| UnknownCounter(s.to_string()))
+    }
+}
+
+/// The result of running a command with `time_cmd_output`
+#[derive(Clone, PartialEq, Debug)]
+pub struct Output {
+    pub timings: Timings,
+    /// Resource usage of the child; `None` on non-unix platforms
+    pub usage: Option<ResourceUsage>,
+    /// The exit status of the command, or `None` if it was killed because
+    /// it exceeded its timeout
+    pub status: Option<ExitStatus>,
+    /// The data the command wrote to stdout (if it was piped)
+    pub stdout: Vec<u8>,
+    /// The data the command wrote to stderr (if it was piped)
+    pub stderr: Vec<u8>,
+    /// The performance counters which were requested and available, in the
+    /// order they were requested.  Always empty on platforms other than
+    /// Linux.
+    pub counters: Vec<(Counter, u64)>,
+}
+
 /// Spawns the given command and times how long it takes to exit.
 ///
-/// The user must ensure that no other child processes are running at the
-/// same time, or else the times will be added.
-///
-/// On Windows the `user_time` and `sys_time` fields will be NaN.
+/// On unix the CPU times have microsecond resolution.  On Windows the
+/// `user_time` and `sys_time` fields will be NaN.
 pub fn time_cmd(cmd: Command) -> Result<(Timings, ExitStatus)> {
+    let out = time_cmd_output(cmd, None)?;
+    // There's no timeout, so it can't have been killed
+    Ok((out.timings, out.status.unwrap()))
+}
+
+/// Like `time_cmd`, but also reports the child's resource usage, collects
+/// its output, and optionally enforces a timeout.
+///
+/// If stdout or stderr are set to `Stdio::piped()`, their contents are
+/// collected and returned.  If a timeout is given and the command is still
+/// running when it expires, the command is killed.  On unix it's put in a
+/// new process group, and the whole group gets SIGKILL; this way any
+/// processes it spawned are cleaned up too.
+pub fn time_cmd_output(cmd: Command, timeout: Option<Duration>) -> Result<Output> {
+    time_cmd_counters(cmd, timeout, &[])
+}
+
+/// Like `time_cmd_output`, but also collects the given performance counters.
+/// See [the crate docs](crate#performance-counters) for details.
+pub fn time_cmd_counters(
+    cmd: Command,
+    timeout: Option<Duration>,
+    counters: &[Counter],
+) -> Result<Output> {
     #[cfg(unix)]
-    let ret = time_cmd_posix(cmd)?;
+    let ret = time_cmd_posix(cmd, timeout, counters)?;
     #[cfg(not(unix))]
-    let ret = time_cmd_fallback(cmd)?;
+    let ret = time_cmd_fallback(cmd, timeout, counters)?;
     Ok(ret)
 }
 
+/// Only spawns a thread if there's something to read, since this happens
+/// while the child is being timed
+fn read_in_background(
+    src: Option<impl Read + Send + 'static>,
+) -> Option<JoinHandle<Result<Vec<u8>>>> {
+    let mut src = src?;
+    Some(std::thread::spawn(move || {
+        let mut buf = vec![];
+        src.read_to_end(&mut buf)?;
+        Ok(buf)
+    }))
+}
+
+fn join_reader(handle: Option<JoinHandle<Result<Vec<u8>>>>) -> Result<Vec<u8>> {
+    match handle {
+        Some(handle) => handle.join().expect("reader thread panicked"),
+        None => Ok(vec![]),
+    }
+}
+
 #[cfg(not(unix))]
-fn time_cmd_fallback(mut cmd: Command) -> Result<(Timings, ExitStatus)> {
+fn time_cmd_fallback(
+    mut cmd: Command,
+    timeout: Option<Duration>,
+    _counters: &[Counter],
+) -> Result<Output> {
     let ts = Instant::now();
-    let status = cmd.spawn()?.wait()?;
+    let mut child = cmd.spawn()?;
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let status = match timeout {
+        None => Some(child.wait()?),
+        Some(timeout) => loop {
+            if let Some(status) = child.try_wait()? {
+                break Some(status);
+            }
+            if ts.elapsed() > timeout {
+                child.kill()?;
+                child.wait()?;
+                break None;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        },
+    };
     let d = ts.elapsed();
-    Ok((
-        Timings {
+    Ok(Output {
+        timings: Timings {
             wall_time: d,
             user_time: std::f64::NAN,
             sys_time: std::f64::NAN,
         },
+        usage: None,
         status,
-    ))
+        stdout: join_reader(stdout)?,
+        stderr: join_reader(stderr)?,
+        counters: vec![],
+    })
 }
 
 #[cfg(unix)]
-fn time_cmd_posix(mut cmd: Command) -> Result<(Timings, ExitStatus)> {
-    // times(2) and sysconf(2) are both POSIX
-    let mut tms_before = libc::tms {
-        tms_utime: 0,
-        tms_stime: 0,
-        tms_cutime: 0,
-        tms_cstime: 0,
-    };
-    let mut tms_after = tms_before;
+fn time_cmd_posix(
+    mut cmd: Command,
+    timeout: Option<Duration>,
+    counters: &[Counter],
+) -> Result<Output> {
+    use std::os::unix::process::CommandExt;
 
-    unsafe { libc::times(&mut tms_before as *mut libc::tms) };
+    if timeout.is_some() {
+        cmd.process_group(0);
+    }
+    // These must be opened on this thread, since this is the thread which
+    // spawns the child
+    #[cfg(target_os = "linux")]
+    let counters = perf::Counters::open(counters);
+    #[cfg(not(target_os = "linux"))]
+    let _ = counters;
+    // The watchdog's thread is started before the clock, so as not to slow
+    // down the run.  It's armed once we know the child's pid.
+    let watchdog = timeout.map(Watchdog::new);
     let ts = Instant::now();
-    let status = cmd.spawn()?.wait()?;
+    let mut child = cmd.spawn()?;
+    if let Some(w) = &watchdog {
+        w.arm(child.id());
+        lock_groups().push(child.id() as libc::pid_t);
+    }
+    let stdout = read_in_background(child.stdout.take());
+    let stderr = read_in_background(child.stderr.take());
+    let pid = child.id() as libc::pid_t;
+    // Wait for the child to exit, but leave it as a zombie for now.  This
+    // keeps its pid (and hence its process group ID) from being reused until
+    // the watchdog has been disarmed, so the watchdog can't kill an
+    // unrelated process group.
+    wait_for_exit(pid)?;
     let d = ts.elapsed();
-    unsafe { libc::times(&mut tms_after as *mut libc::tms) };
+    let timed_out = watchdog.is_some_and(|w| w.disarm());
+    // Like the watchdog, this must be done while the pid is still ours
+    lock_groups().retain(|&x| x != pid);
+    // We reap the child ourselves, rather than with `child.wait()`, because
+    // we want its rusage
+    let (status, rusage) = wait4(pid)?;
 
-    let ticks_per_sec = unsafe { libc::sysconf(libc::_SC_CLK_TCK) } as f64;
-    let utime = (tms_after.tms_cutime - tms_before.tms_cutime) as f64 / ticks_per_sec;
-    let stime = (tms_after.tms_cstime - tms_before.tms_cstime) as f64 / ticks_per_sec;
+    let secs = |t: libc::timeval| t.tv_sec as f64 + t.tv_usec as f64 / 1_000_000.0;
+    // Linux reports maxrss in kilobytes, but macOS reports it in bytes
+    #[cfg(target_os = "macos")]
+    let max_rss_kb = rusage.ru_maxrss as u64 / 1024;
+    #[cfg(not(target_os = "macos"))]
+    let max_rss_kb = rusage.ru_maxrss as u64;
 
-    Ok((
-        Timings {
+    Ok(Output {
+        timings: Timings {
             wall_time: d,
-            user_time: utime,
-            sys_time: stime,
+            user_time: secs(rusage.ru_utime),
+            sys_time: secs(rusage.ru_stime),
         },
-        status,
-    ))
+        usage: Some(ResourceUsage {
+            max_rss_kb,
+            minor_faults: rusage.ru_minflt as u64,
+            major_faults: rusage.ru_majflt as u64,
+            voluntary_context_switches: rusage.ru_nvcsw as u64,
+            involuntary_context_switches: rusage.ru_nivcsw as u64,
+            block_inputs: rusage.ru_inblock as u64,
+            block_outputs: rusage.ru_oublock as u64,
+        }),
+        status: if timed_out { None } else { Some(status) },
+        stdout: join_reader(stdout)?,
+        stderr: join_reader(stderr)?,
+        #[cfg(target_os = "linux")]
+        counters: counters.read(),
+        #[cfg(not(target_os = "linux"))]
+        counters: vec![],
+    })
+}
+
+/// Waits for the given child to exit, without reaping it
+#[cfg(unix)]
+fn wait_for_exit(pid: libc::pid_t) -> Result<()> {
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    loop {
+        let flags = libc::WEXITED | libc::WNOWAIT;
+        let ret = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, flags) };
+        if ret != -1 {
+            return Ok(());
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// Reaps the given child, returning its rusage
+#[cfg(unix)]
+fn wait4(pid: libc::pid_t) -> Result<(ExitStatus, libc::rusage)> {
+    use std::os::unix::process::ExitStatusExt;
+    let mut status = 0;
+    let mut rusage = unsafe { std::mem::zeroed::<libc::rusage>() };
+    loop {
+        let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+        if ret != -1 {
+            return Ok((ExitStatus::from_raw(status), rusage));
+        }
+        let err = std::io::Error::last_os_error();
+        if err.kind() != std::io::ErrorKind::Interrupted {
+            return Err(err);
+        }
+    }
+}
+
+/// The process groups of the commands which are running with a timeout
+#[cfg(unix)]
+static GROUPS: std::sync::Mutex<Vec<libc::pid_t>> = std::sync::Mutex::new(Vec::new());
+
+#[cfg(unix)]
+fn lock_groups() -> std::sync::MutexGuard<'static, Vec<libc::pid_t>> {
+    GROUPS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Kills any commands which are currently being run with a timeout (and
+/// anything they spawned).  They'll be reported as having exited because of
+/// SIGKILL.
+///
+/// These commands are in their own process groups, so they don't get the
+/// terminal's SIGINT.  Call this when your program is interrupted if you
+/// don't want them to outlive it.
+#[cfg(unix)]
+pub fn kill_running() {
+    for &pgid in lock_groups().iter() {
+        unsafe { libc::kill(-pgid, libc::SIGKILL) };
+    }
+}
+
+/// Kills a process group if it isn't disarmed before the timeout expires.
+/// The timeout starts when it's armed.
+#[cfg(unix)]
+struct Watchdog {
+    /// Sends the pgid to arm it; dropping it disarms it
+    tx: std::sync::mpsc::Sender<u32>,
+    handle: JoinHandle<bool>,
+}
+
+#[cfg(unix)]
+impl Watchdog {
+    fn new(timeout: Duration) -> Watchdog {
+        use std::sync::mpsc::*;
+        let (tx, rx) = channel::<u32>();
+        let handle = std::thread::spawn(move || {
+            // If we're dropped without being armed, the spawn failed
+            let Ok(pgid) = rx.recv() else {
+                return false;
+            };
+            match rx.recv_timeout(timeout) {
+                Err(RecvTimeoutError::Timeout) => {
+                    unsafe { libc::kill(-(pgid as libc::pid_t), libc::SIGKILL) };
+                    true
+                }
+                _ => false,
+            }
+        });
+        Watchdog { tx, handle }
+    }
+
+    fn arm(&self, pgid: u32) {
+        let _ = self.tx.send(pgid);
+    }
+
+    /// Returns true if the watchdog had already fired
+    fn disarm(self) -> bool {
+        drop(self.tx);
+        self.handle.join().unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Stdio;
+
+    #[test]
+    fn test_output() {
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("echo foo; echo bar >&2")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        let out = time_cmd_output(cmd, None).unwrap();
+        assert!(out.status.unwrap().success());
+        assert_eq!(out.stdout, b"foo\n");
+        assert_eq!(out.stderr, b"bar\n");
+    }
+
+    #[test]
+    fn test_rusage() {
+        // Busy-loop for a bit, so there's some CPU time to measure
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done");
+        let out = time_cmd_output(cmd, None).unwrap();
+        assert!(out.status.unwrap().success());
+        assert!(out.timings.user_time + out.timings.sys_time > 0.0);
+        assert!(out.usage.unwrap().max_rss_kb > 0);
+    }
+
+    #[test]
+    fn test_concurrent_accounting() {
+        // Burn CPU in one thread while another times a sleep.  The sleep's
+        // CPU time shouldn't include the busy loop's.
+        let busy = std::thread::spawn(|| {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c")
+                .arg("i=0; while [ $i -lt 100000 ]; do i=$((i+1)); done");
+            time_cmd(cmd).unwrap().0
+        });
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("sleep 0.5");
+        let (sleep, _) = time_cmd(cmd).unwrap();
+        let busy = busy.join().unwrap();
+        assert!(busy.user_time > 0.05, "{:?}", busy);
+        assert!(sleep.user_time < 0.05, "{:?}", sleep);
+    }
+
+    #[test]
+    fn test_timeout_kills_group() {
+        let _serial = KILLING.lock();
+        // The grandchild holds stdout open, so if it survived we'd never
+        // finish reading
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c").arg("sleep 10 & wait").stdout(Stdio::piped());
+        let out = time_cmd_output(cmd, Some(Duration::from_millis(100))).unwrap();
+        assert_eq!(out.status, None);
+        assert!(out.timings.wall_time < Duration::from_secs(5));
+    }
+
+    /// `kill_running` kills every command with a timeout, including those
+    /// in other tests
+    static KILLING: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+    #[test]
+    fn test_kill_running() {
+        use std::os::unix::process::ExitStatusExt;
+        let _serial = KILLING.lock();
+        let handle = std::thread::spawn(|| {
+            let mut cmd = Command::new("/bin/sh");
+            cmd.arg("-c").arg("sleep 10 & wait").stdout(Stdio::piped());
+            time_cmd_output(cmd, Some(Duration::from_secs(60))).unwrap()
+        });
+        std::thread::sleep(Duration::from_millis(200));
+        kill_running();
+        let out = handle.join().unwrap();
+        assert_eq!(out.status.unwrap().signal(), Some(libc::SIGKILL));
+        assert!(out.timings.wall_time < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn test_counters() {
+        assert_eq!("branch_misses".parse(), Ok(Counter::BranchMisses));
+        assert_eq!("task-clock".parse(), Ok(Counter::TaskClock));
+        assert!("cache-misses".parse::<Counter>().is_err());
+
+        let mut cmd = Command::new("/bin/sh");
+        cmd.arg("-c")
+            .arg("i=0; while [ $i -lt 20000 ]; do i=$((i+1)); done");
+        let out = time_cmd_counters(cmd, None, &Counter::ALL).unwrap();
+        assert!(out.status.unwrap().success());
+        // perf_event_open may not be allowed here at all, so we can only
+        // check the counters which we did get
+        for (counter, value) in out.counters {
+            match counter {
+                Counter::ContextSwitches => (),
+                _ => assert!(value > 0, "{}: {}", counter, value),
+            }
+        }
+    }
 }
@@ -0,0 +1,155 @@
+//! Performance counters, via `perf_event_open(2)`
+//!
+//! We can't open counters on the child directly, because `Command::spawn`
+//! doesn't return until the child has already exec'd.  Instead, we open them
+//! on the calling thread, disabled, with `inherit` and `enable_on_exec` set.
+//! The child inherits a copy of each counter when it's forked, and the copy
+//! switches on when it execs.  The calling thread never execs, so it doesn't
+//! count anything itself.  When the child exits, its counts are added to
+//! ours.
+
+use crate::Counter;
+use std::io::{Error, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+
+const PERF_TYPE_HARDWARE: u32 = 0;
+const PERF_TYPE_SOFTWARE: u32 = 1;
+
+const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+const PERF_COUNT_HW_BRANCH_INSTRUCTIONS: u64 = 4;
+const PERF_COUNT_HW_BRANCH_MISSES: u64 = 5;
+const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+const PERF_COUNT_SW_PAGE_FAULTS: u64 = 2;
+const PERF_COUNT_SW_CONTEXT_SWITCHES: u64 = 3;
+
+const PERF_FORMAT_TOTAL_TIME_ENABLED: u64 = 1 << 0;
+const PERF_FORMAT_TOTAL_TIME_RUNNING: u64 = 1 << 1;
+
+// Bits of `PerfEventAttr::flags`
+const DISABLED: u64 = 1 << 0;
+const INHERIT: u64 = 1 << 1;
+const EXCLUDE_KERNEL: u64 = 1 << 5;
+const EXCLUDE_HV: u64 = 1 << 6;
+const ENABLE_ON_EXEC: u64 = 1 << 12;
+
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+/// The first version of `struct perf_event_attr` (`PERF_ATTR_SIZE_VER0`).
+/// The kernel accepts older versions of the struct, and we don't need any
+/// of the newer fields.
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// A set of counters which will be inherited by the next child spawned by
+/// the calling thread
+pub struct Counters(Vec<(Counter, OwnedFd)>);
+
+impl Counters {
+    /// Opens the given counters on the calling thread.  Counters which
+    /// aren't available (eg. hardware counters in a VM without a virtual
+    /// PMU) are skipped.
+    pub fn open(counters: &[Counter]) -> Counters {
+        Counters(
+            counters
+                .iter()
+                .filter_map(|&c| Some((c, open(c).ok()?)))
+                .collect(),
+        )
+    }
+
+    /// Reads the counters.  Only call this after the child has been reaped:
+    /// its counts aren't added to ours until it exits.
+    pub fn read(self) -> Vec<(Counter, u64)> {
+        self.0
+            .into_iter()
+            .filter_map(|(c, fd)| Some((c, read(&fd).ok()??)))
+            .collect()
+    }
+}
+
+fn open(counter: Counter) -> Result<OwnedFd> {
+    let (type_, config) = match counter {
+        Counter::Instructions => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_INSTRUCTIONS),
+        Counter::Cycles => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_CPU_CYCLES),
+        Counter::Branches => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_INSTRUCTIONS),
+        Counter::BranchMisses => (PERF_TYPE_HARDWARE, PERF_COUNT_HW_BRANCH_MISSES),
+        Counter::TaskClock => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_TASK_CLOCK),
+        Counter::ContextSwitches => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_CONTEXT_SWITCHES),
+        Counter::PageFaults => (PERF_TYPE_SOFTWARE, PERF_COUNT_SW_PAGE_FAULTS),
+    };
+    let mut attr = PerfEventAttr {
+        type_,
+        size: std::mem::size_of::<PerfEventAttr>() as u32,
+        config,
+        read_format: PERF_FORMAT_TOTAL_TIME_ENABLED | PERF_FORMAT_TOTAL_TIME_RUNNING,
+        flags: DISABLED | INHERIT | ENABLE_ON_EXEC,
+        ..PerfEventAttr::default()
+    };
+    match perf_event_open(&attr) {
+        // Unprivileged users usually aren't allowed to count kernel events
+        // (see perf_event_paranoid), so try again with just userspace
+        Err(e) if e.raw_os_error() == Some(libc::EACCES) => {
+            attr.flags |= EXCLUDE_KERNEL | EXCLUDE_HV;
+            perf_event_open(&attr)
+        }
+        x => x,
+    }
+}
+
+fn perf_event_open(attr: &PerfEventAttr) -> Result<OwnedFd> {
+    // pid = 0, cpu = -1: the calling thread, on any CPU
+    let fd = unsafe {
+        libc::syscall(
+            libc::SYS_perf_event_open,
+            attr as *const PerfEventAttr,
+            0 as libc::pid_t,
+            -1 as libc::c_int,
+            -1 as libc::c_int,
+            PERF_FLAG_FD_CLOEXEC,
+        )
+    };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    Ok(unsafe { OwnedFd::from_raw_fd(fd as libc::c_int) })
+}
+
+/// Returns `None` if the counter never got scheduled
+fn read(fd: &OwnedFd) -> Result<Option<u64>> {
+    let mut buf = [0u64; 3];
+    let n = unsafe {
+        libc::read(
+            fd.as_raw_fd(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            std::mem::size_of_val(&buf),
+        )
+    };
+    if n == -1 {
+        return Err(Error::last_os_error());
+    }
+    let [value, enabled, running] = buf;
+    if running == 0 {
+        return Ok(None);
+    }
+    // If there were more counters than the PMU has registers, the kernel
+    // will have multiplexed them, so scale up to estimate the true count
+    if running < enabled {
+        return Ok(Some(
+            (value as f64 * enabled as f64 / running as f64) as u64,
+        ));
+    }
+    Ok(Some(value))
+}